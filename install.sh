@@ -2,9 +2,12 @@
 
 cd service
 rm -rf ./target
-cargo build --release
+cargo build --release --features pigpio
 cp ./target/release/service /usr/local/bin/radiator
+cp ./target/release/radiatorctl /usr/local/bin/radiatorctl
 cd ../
+mkdir -p /etc/radiator
+[ -f /etc/radiator/config.toml ] || cp ./config.toml /etc/radiator/config.toml
 cp ./radiator.service /etc/systemd/system/radiator.service
 systemctl enable radiator.service
 systemctl start radiator.service
@@ -0,0 +1,1283 @@
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{
+    Path,
+    PathBuf
+};
+use serde::{
+    Deserialize,
+    Serialize
+};
+use super::curve::FanCurve;
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+/// Default config file location.
+pub const CONFIG_PATH: &str = "/etc/radiator/config.toml";
+
+/// GPIO pins with hardware PWM.
+pub const HARDWARE_PWM_PINS: [u8; 4] = [12, 13, 18, 19];
+
+/// Hardware PWM duty-cycle steps between off and fully on.
+pub const HARDWARE_PWM_RANGE: u32 = 1_000_000;
+
+/// Highest hardware PWM frequency(Hz) pigpio accepts, the PWM
+/// clock is 250MHz and a period needs two cycles. Fans won't
+/// get a clean signal above 30MHz anyway.
+pub const HARDWARE_PWM_FREQUENCY: u32 = 125_000_000;
+
+const USAGE: &str = "\
+Usage: radiator [OPTIONS]
+
+Options:
+    -p, --pin <PIN>        fan PWM pin, overrides fan.pin (env: RADIATOR_PIN)
+    -d, --delay <SECS>     loop cycle in seconds, overrides poll_interval (env: RADIATOR_DELAY)
+    -c, --config <PATH>    config file (env: RADIATOR_CONFIG, default: /etc/radiator/config.toml)
+    -l, --log-level <LVL>  error, warn, info or debug, overrides log.level (env: RADIATOR_LOG_LEVEL)
+    -h, --help             print this help
+
+Precedence: flags > environment > config file > defaults.";
+
+/// Service configuration.
+///
+/// Loaded from a TOML file, every section and field is optional
+/// and falls back to the built-in defaults:
+///
+/// ```toml
+/// poll_interval = 10
+/// mode = "curve"
+///
+/// [sensor]
+/// source = "vcgencmd"
+/// zone = 0
+/// sysfs_root = "/sys/class/thermal"
+/// vcgencmd = "vcgencmd"
+/// timeout = 2000
+///
+/// [fan]
+/// driver = "pigpio"
+/// pigpio_mode = "in-process"
+/// pigpiod_address = "127.0.0.1:8888"
+/// pin = 12
+/// pwm = "hardware"
+/// frequency = 25000
+/// range = 255
+/// chip = 0
+/// channel = 0
+/// sysfs_root = "/sys/class/pwm"
+/// min_duty = 0.0
+/// below_min = "off"
+/// kickstart = 0
+/// ramp_up = 10.0
+/// ramp_down = 5.0
+/// ramp_tick = 250
+///
+/// # optional, no speed feedback when absent.
+/// [fan.tach]
+/// source = "gpiochip"
+/// pin = 6
+/// chip = "/dev/gpiochip0"
+/// pulses_per_rev = 2
+/// window = 3
+///
+/// # optional, needs `fan.tach`, no stall detection when absent.
+/// [fan.stall]
+/// spin_threshold = 20.0
+/// max_rpm = 3000.0
+/// min_ratio = 0.3
+/// kicks = 2
+/// kick_duration = 2000
+/// alert_command = "/usr/local/bin/fan-alert"
+///
+/// [curve]
+/// points = [[40.0, 0.0], [60.0, 100.0]]
+///
+/// [failsafe]
+/// policy = "duty"
+/// failures = 3
+/// duty = 100.0
+///
+/// [shutdown]
+/// duty = 100.0
+///
+/// [pid]
+/// setpoint = 55.0
+/// kp = 10.0
+/// ki = 0.1
+/// kd = 20.0
+/// derivative_filter = 5.0
+/// min_duty = 0.0
+/// max_duty = 100.0
+///
+/// # optional, the controller alone decides when absent.
+/// [hysteresis]
+/// on = 42.0
+/// off = 38.0
+/// min_dwell = 30
+/// min_duty = 20.0
+///
+/// [history]
+/// capacity = 8640
+/// file = "/var/lib/radiator/history.bin"
+/// save_interval = 300
+///
+/// [log]
+/// level = "info"
+/// format = "plain"
+/// rate_limit = 3
+/// rate_window = 300
+///
+/// # optional, no HTTP listener when absent.
+/// [metrics]
+/// address = "127.0.0.1:9183"
+///
+/// # optional, no control socket when absent.
+/// [control]
+/// path = "/run/radiator.sock"
+/// mode = "0660"
+/// group = "radiator"
+/// critical_temp = 75.0
+/// safe_duty = 100.0
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Loop cycle(secs).
+    pub poll_interval: u64,
+    pub mode: Mode,
+    pub sensor: SensorConfig,
+    pub fan: FanConfig,
+    pub curve: CurveConfig,
+    pub failsafe: FailsafeConfig,
+    pub shutdown: ShutdownConfig,
+    pub pid: PidConfig,
+    pub hysteresis: Option<HysteresisConfig>,
+    pub history: HistoryConfig,
+    pub log: LogConfig,
+    pub metrics: Option<MetricsConfig>,
+    pub control: Option<ControlConfig>,
+}
+
+/// How the duty-cycle is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Open loop, the duty follows `curve`.
+    Curve,
+    /// Closed loop, `pid` holds the temperature at its setpoint.
+    Pid,
+}
+
+/// Temperature sensor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorConfig {
+    pub source: SensorSource,
+    /// Thermal zone for `sysfs`, an index or a `type` name.
+    pub zone: Zone,
+    /// Thermal zones directory for `sysfs`.
+    pub sysfs_root: PathBuf,
+    /// Program run by `vcgencmd`.
+    pub vcgencmd: PathBuf,
+    /// How long(ms) `vcgencmd` may take before it is killed.
+    pub timeout: u64,
+    /// Readings(°C) replayed in a loop by `script`.
+    pub script: Vec<f32>,
+}
+
+/// Where the soc temperature is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SensorSource {
+    /// `vcgencmd measure_temp`.
+    Vcgencmd,
+    /// `/sys/class/thermal/thermal_zoneN/temp`.
+    Sysfs,
+    /// Replays `sensor.script`, in °C.
+    Script,
+}
+
+/// Thermal zone selector.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Zone {
+    /// `thermal_zoneN`.
+    Index(u32),
+    /// The zone whose `type` file holds this name, e.g. `cpu-thermal`.
+    Type(String),
+}
+
+/// Fan PWM output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanConfig {
+    pub driver: FanDriverKind,
+    pub pigpio_mode: PigpioMode,
+    /// pigpiod `host:port` or unix socket path for `daemon`.
+    pub pigpiod_address: String,
+    /// BCM GPIO number.
+    pub pin: u8,
+    /// PWM generator for `pigpio`.
+    pub pwm: PwmMode,
+    /// PWM frequency(Hz).
+    pub frequency: u32,
+    /// Duty-cycle steps between off and fully on.
+    pub range: u32,
+    /// `pwmchipN` for `sysfs`.
+    pub chip: u32,
+    /// `pwmM` channel of the chip for `sysfs`, GPIO12 and GPIO18 
+    /// are channel 0, GPIO13 and GPIO19 are channel 1.
+    pub channel: u32,
+    /// PWM chips directory for `sysfs`.
+    pub sysfs_root: PathBuf,
+    /// Lowest duty(%) the fan keeps spinning at.
+    pub min_duty: f32,
+    /// What a duty between 0 and `min_duty` becomes.
+    pub below_min: BelowMin,
+    /// How long(ms) a starting fan runs at full duty, 0 disables.
+    pub kickstart: u64,
+    /// Rising duty rate limit(%/s), unset jumps to the new duty.
+    pub ramp_up: Option<f32>,
+    /// Falling duty rate limit(%/s), unset jumps to the new duty.
+    pub ramp_down: Option<f32>,
+    /// Step interval(ms) of a ramp between polls.
+    pub ramp_tick: u64,
+    /// Optional tachometer input.
+    pub tach: Option<TachConfig>,
+    /// Optional stall detection, needs `tach`.
+    pub stall: Option<StallConfig>,
+}
+
+/// Fan tachometer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TachConfig {
+    #[serde(default = "TachConfig::default_source")]
+    pub source: TachSource,
+    /// BCM GPIO number of the tach line.
+    pub pin: u8,
+    /// GPIO character device for `gpiochip`.
+    #[serde(default = "TachConfig::default_chip")]
+    pub chip: PathBuf,
+    /// Tach pulses per revolution, 2 for PC fans.
+    #[serde(default = "TachConfig::default_pulses_per_rev")]
+    pub pulses_per_rev: u32,
+    /// Sliding window(secs) the speed is measured over.
+    #[serde(default = "TachConfig::default_window")]
+    pub window: u64,
+    /// Speeds(rpm) replayed in a loop by `script`.
+    #[serde(default)]
+    pub script: Vec<f32>,
+}
+
+/// What a duty below `fan.min_duty` becomes.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BelowMin {
+    /// The fan stops.
+    Off,
+    /// The fan runs at `min_duty`.
+    Min,
+}
+
+/// Fan stall and failure detection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StallConfig {
+    /// Duty(%) from which the fan must spin.
+    pub spin_threshold: f32,
+    /// Speed(rpm) at full duty, unset only catches a stopped fan.
+    pub max_rpm: Option<f32>,
+    /// Fraction of the expected speed below which the fan is stalled.
+    pub min_ratio: f32,
+    /// Full-power kicks tried before the fan is failed.
+    pub kicks: u32,
+    /// How long(ms) a kick lasts.
+    pub kick_duration: u64,
+    /// Shell command run when the fan fails or recovers.
+    pub alert_command: Option<String>,
+}
+
+/// Where tach pulses are read from.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TachSource {
+    /// Kernel GPIO character device edge events.
+    Gpiochip,
+    /// pigpio alerts, needs the `pigpio` feature.
+    Pigpio,
+    /// Synthesizes edges for the speeds of `fan.tach.script`.
+    Script,
+}
+
+/// How the fan PWM is driven.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FanDriverKind {
+    /// pigpio, in-process or through the daemon, see `pigpio_mode`.
+    Pigpio,
+    /// Kernel hardware PWM, `/sys/class/pwm/pwmchipN/pwmM`.
+    Sysfs,
+    /// Keeps the duty in memory, drives nothing.
+    Memory,
+}
+
+/// How pigpio is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PigpioMode {
+    /// Link the C library and own the GPIO hardware,
+    /// needs the `pigpio` feature.
+    InProcess,
+    /// Talk to a running `pigpiod` over its socket.
+    Daemon,
+}
+
+/// How pigpio generates the PWM.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PwmMode {
+    /// PWM peripheral, up to 125MHz and 1000000 steps,
+    /// only on GPIO12, GPIO13, GPIO18 and GPIO19.
+    Hardware,
+    /// DMA-timed `gpioPWM` on any GPIO, fixed sample-rate 
+    /// frequencies up to 8000Hz and 25-40000 steps.
+    Software,
+}
+
+/// Fan curve.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CurveConfig {
+    /// `[temperature(°C), duty(%)]` pairs in ascending temperature.
+    pub points: Vec<[f32; 2]>,
+}
+
+/// What to do when the temperature cannot be read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailsafeConfig {
+    /// What happens after `failures` consecutive failed reads.
+    pub policy: FailsafePolicy,
+    /// Consecutive failed reads tolerated, the duty is held meanwhile.
+    pub failures: u32,
+    /// Duty(%) applied by the `duty` and `exit` policies.
+    pub duty: f32,
+}
+
+/// What the monitor does once the temperature is lost.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailsafePolicy {
+    /// Run the fan at `failsafe.duty` until readings come back.
+    Duty,
+    /// Keep the last duty applied until readings come back.
+    Hold,
+    /// Apply `failsafe.duty` and exit with an error, so that
+    /// systemd restarts the service.
+    Exit,
+}
+
+/// Fan state left behind when the service stops.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Duty(%) applied on SIGTERM or SIGINT.
+    pub duty: f32,
+}
+
+/// PID controller.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PidConfig {
+    /// Target temperature(°C).
+    pub setpoint: f32,
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    /// Derivative low-pass time constant(secs).
+    pub derivative_filter: f32,
+    /// Lowest duty(%) the controller outputs.
+    pub min_duty: f32,
+    /// Highest duty(%) the controller outputs.
+    pub max_duty: f32,
+}
+
+/// Fan on/off hysteresis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HysteresisConfig {
+    /// The fan turns on above this temperature(°C).
+    pub on: f32,
+    /// The fan turns off below this temperature(°C).
+    pub off: f32,
+    /// Minimum time(secs) the fan stays on or off.
+    #[serde(default)]
+    pub min_dwell: u64,
+    /// Lowest duty(%) while the fan is on, so that it keeps spinning
+    /// down to `off` even where the controller asks for less. Raised
+    /// to `fan.min_duty` when that is higher.
+    #[serde(default = "HysteresisConfig::default_min_duty")]
+    pub min_duty: f32,
+}
+
+/// Prometheus exporter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// `host:port` serving `/metrics`, only read at startup.
+    pub address: String,
+}
+
+/// Temperature and duty history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Samples kept, one per poll, the oldest are dropped first.
+    /// 0 keeps no history.
+    pub capacity: usize,
+    /// File the samples are saved to and restored from at startup,
+    /// kept in memory only when unset.
+    pub file: Option<PathBuf>,
+    /// Save cycle(secs), the history is also saved when stopping.
+    pub save_interval: u64,
+}
+
+/// Service log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// Most verbose level written.
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Warnings and errors written per call site within `rate_window`,
+    /// the rest are counted and reported with the next one, 0 for
+    /// no limit.
+    pub rate_limit: u32,
+    /// Rate limit window(secs).
+    pub rate_window: u64,
+}
+
+/// Log verbosity, from the least to the most verbose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    /// Also every temperature reading.
+    Debug,
+}
+
+/// Where and how log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line of text per record on stderr.
+    Plain,
+    /// One JSON object per record on stderr.
+    Json,
+    /// Native journald records with fields like `TEMP_C` and `DUTY`.
+    Journald,
+}
+
+/// Control socket.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Unix socket path, only read at startup.
+    pub path: PathBuf,
+    /// Octal permissions of the socket.
+    pub mode: String,
+    /// Group owning the socket, unchanged when unset.
+    pub group: Option<String>,
+    /// Temperature(°C) from which a manual duty is raised to `safe_duty`.
+    pub critical_temp: f32,
+    /// Lowest manual duty(%) at or above `critical_temp`.
+    pub safe_duty: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            poll_interval: 10,
+            mode: Mode::Curve,
+            sensor: SensorConfig::default(),
+            fan: FanConfig::default(),
+            curve: CurveConfig::default(),
+            failsafe: FailsafeConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            pid: PidConfig::default(),
+            hysteresis: None,
+            history: HistoryConfig::default(),
+            log: LogConfig::default(),
+            metrics: None,
+            control: None,
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("/run/radiator.sock"),
+            mode: "0660".to_string(),
+            group: None,
+            critical_temp: 75.0,
+            safe_duty: 100.0,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 8640,
+            file: None,
+            save_interval: 300,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::Info,
+            format: LogFormat::Plain,
+            rate_limit: 3,
+            rate_window: 300,
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:9183".to_string(),
+        }
+    }
+}
+
+impl Default for SensorConfig {
+    fn default() -> Self {
+        Self {
+            source: SensorSource::Vcgencmd,
+            zone: Zone::Index(0),
+            sysfs_root: PathBuf::from("/sys/class/thermal"),
+            vcgencmd: PathBuf::from("vcgencmd"),
+            timeout: 2000,
+            script: Vec::new(),
+        }
+    }
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        Self {
+            driver: FanDriverKind::Pigpio,
+            pigpio_mode: PigpioMode::InProcess,
+            pigpiod_address: "127.0.0.1:8888".to_string(),
+            pin: 12,
+            pwm: PwmMode::Hardware,
+            frequency: 25000,
+            range: 255,
+            chip: 0,
+            channel: 0,
+            sysfs_root: PathBuf::from("/sys/class/pwm"),
+            min_duty: 0.0,
+            below_min: BelowMin::Off,
+            kickstart: 0,
+            ramp_up: None,
+            ramp_down: None,
+            ramp_tick: 250,
+            tach: None,
+            stall: None,
+        }
+    }
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            spin_threshold: 20.0,
+            max_rpm: None,
+            min_ratio: 0.3,
+            kicks: 2,
+            kick_duration: 2000,
+            alert_command: None,
+        }
+    }
+}
+
+impl Default for CurveConfig {
+    fn default() -> Self {
+        Self {
+            points: vec![[40.0, 0.0], [60.0, 100.0]],
+        }
+    }
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self {
+            policy: FailsafePolicy::Duty,
+            failures: 3,
+            duty: 100.0,
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            duty: 100.0,
+        }
+    }
+}
+
+impl Default for PidConfig {
+    fn default() -> Self {
+        Self {
+            setpoint: 55.0,
+            kp: 10.0,
+            ki: 0.1,
+            kd: 20.0,
+            derivative_filter: 5.0,
+            min_duty: 0.0,
+            max_duty: 100.0,
+        }
+    }
+}
+
+impl HysteresisConfig {
+    fn default_min_duty() -> f32 {
+        20.0
+    }
+}
+
+impl TachConfig {
+    fn default_source() -> TachSource {
+        TachSource::Gpiochip
+    }
+
+    fn default_chip() -> PathBuf {
+        PathBuf::from("/dev/gpiochip0")
+    }
+
+    fn default_pulses_per_rev() -> u32 {
+        2
+    }
+
+    fn default_window() -> u64 {
+        3
+    }
+}
+
+/// Settings given on the command line or in the environment,
+/// every field may be absent.
+#[derive(Debug, Default)]
+struct Overrides {
+    pin: Option<u8>,
+    delay: Option<u64>,
+    config: Option<PathBuf>,
+    log_level: Option<LogLevel>,
+}
+
+impl Overrides {
+    /// Fill the unset fields from a lower layer.
+    fn or(self, other: Overrides) -> Overrides {
+        Overrides {
+            pin: self.pin.or(other.pin),
+            delay: self.delay.or(other.delay),
+            config: self.config.or(other.config),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+}
+
+impl FanConfig {
+    /// Whether both configurations drive the same hardware the
+    /// same way, otherwise the driver has to be reopened.
+    #[rustfmt::skip]
+    pub fn same_backend(&self, other: &FanConfig) -> bool {
+        self.driver == other.driver
+            && self.pigpio_mode == other.pigpio_mode
+            && self.pigpiod_address == other.pigpiod_address
+            && self.pin == other.pin
+            && self.pwm == other.pwm
+            && self.frequency == other.frequency
+            && self.range == other.range
+            && self.chip == other.chip
+            && self.channel == other.channel
+            && self.sysfs_root == other.sysfs_root
+            && self.tach == other.tach
+    }
+
+    /// Whether both configurations claim hardware only one driver
+    /// can hold at a time: pigpio owns the GPIOs, and a sysfs channel
+    /// or a tach line can't be opened twice.
+    #[rustfmt::skip]
+    pub fn shares_hardware(&self, other: &FanConfig) -> bool {
+        let pigpio = self.driver == FanDriverKind::Pigpio && other.driver == FanDriverKind::Pigpio;
+        let channel = self.driver == FanDriverKind::Sysfs
+            && other.driver == FanDriverKind::Sysfs
+            && (&self.sysfs_root, self.chip, self.channel) == (&other.sysfs_root, other.chip, other.channel);
+        pigpio || channel || (self.tach.is_some() && other.tach.is_some())
+    }
+}
+
+impl CurveConfig {
+    /// Points as `(temperature, duty)` pairs.
+    pub fn points(&self) -> Vec<(f32, f32)> {
+        self.points.iter().map(|[temp, duty]| (*temp, *duty)).collect()
+    }
+}
+
+impl Config {
+    /// Resolve the runtime configuration.
+    ///
+    /// Command-line flags take precedence over environment variables,
+    /// which take precedence over the config file, which takes
+    /// precedence over the built-in defaults.
+    ///
+    /// Returns `None` when help was requested.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let config = Config::load().unwrap().unwrap();
+    /// Monitor::builder(config).unwrap();
+    /// ```
+    pub fn load() -> Result<Option<Self>> {
+        Self::resolve(env::args().skip(1), |key| env::var(key))
+    }
+
+    /// Resolve the configuration from the given flags and environment
+    /// variable lookup, see `load`.
+    #[rustfmt::skip]
+    fn resolve<I, V>(args: I, var: V) -> Result<Option<Self>>
+    where
+        I: Iterator<Item = String>,
+        V: Fn(&str) -> Result<String, env::VarError>
+    {
+        let flags = match parse_args(args)? {
+            Some(flags) => flags,
+            None => {
+                println!("{}", USAGE);
+                return Ok(None)
+            }
+        };
+
+        let overrides = flags.or(parse_env(var)?);
+        let mut config = match &overrides.config {
+            Some(path) => Self::from_file(path)?,
+            None => match fs::read_to_string(CONFIG_PATH) {
+                Ok(text) => Self::parse(&text)
+                    .with_context(|| format!("invalid config file {:?}", CONFIG_PATH))?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+                Err(e) => return Err(e)
+                    .with_context(|| format!("cannot read config file {:?}", CONFIG_PATH))
+            }
+        };
+
+        if let Some(pin) = overrides.pin {
+            config.fan.pin = pin;
+        }
+
+        if let Some(delay) = overrides.delay {
+            config.poll_interval = delay;
+        }
+
+        if let Some(level) = overrides.log_level {
+            config.log.level = level;
+        }
+
+        config.validate()?;
+        Ok(Some(config))
+    }
+
+    /// Read and parse a config file.
+    #[rustfmt::skip]
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("cannot read config file {:?}", path))?;
+        Self::parse(&text)
+            .with_context(|| format!("invalid config file {:?}", path))
+    }
+
+    /// Parse a TOML document.
+    ///
+    /// Only checks the document shape, see `validate` for the values.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let config = Config::parse("[fan]\npin = 13").unwrap();
+    /// assert_eq!(config.fan.pin, 13);
+    /// ```
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Check every value.
+    ///
+    /// All problems are reported at once, one per line,
+    /// prefixed with the field path.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let config = Config::parse("[fan]\npin = 17").unwrap();
+    /// let err = config.validate().unwrap_err();
+    /// // fan.pin 17 is not a hardware PWM pin [12, 13, 18, 19]
+    /// ```
+    #[rustfmt::skip]
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = Vec::new();
+
+        if self.poll_interval == 0 {
+            errors.push("poll_interval must be at least 1 second".to_string());
+        }
+
+        if let Zone::Type(name) = &self.sensor.zone {
+            if name.trim().is_empty() {
+                errors.push("sensor.zone type name must not be empty".to_string());
+            }
+        }
+
+        if self.sensor.timeout == 0 {
+            errors.push("sensor.timeout must be at least 1ms".to_string());
+        }
+
+        if self.sensor.source == SensorSource::Script && self.sensor.script.is_empty() {
+            errors.push("sensor.script must not be empty when sensor.source is \"script\"".to_string());
+        }
+
+        let pigpio = self.fan.driver == FanDriverKind::Pigpio;
+        let in_process = pigpio && self.fan.pigpio_mode == PigpioMode::InProcess;
+        if in_process && !cfg!(feature = "pigpio") {
+            errors.push(
+                "fan.pigpio_mode \"in-process\" is not available, build with `--features pigpio` or use \"daemon\"".to_string()
+            );
+        }
+
+        if pigpio && self.fan.pigpio_mode == PigpioMode::Daemon && self.fan.pigpiod_address.trim().is_empty() {
+            errors.push("fan.pigpiod_address must not be empty".to_string());
+        }
+
+        let software = pigpio && self.fan.pwm == PwmMode::Software;
+        if pigpio && !software && !HARDWARE_PWM_PINS.contains(&self.fan.pin) {
+            errors.push(format!(
+                "fan.pin {} is not a hardware PWM pin {:?}, use fan.pwm = \"software\" for other pins",
+                self.fan.pin,
+                HARDWARE_PWM_PINS
+            ));
+        }
+
+        if software && self.fan.pin > 31 {
+            errors.push(format!("fan.pin {} is outside 0-31", self.fan.pin));
+        }
+
+        if pigpio && !software && !(1..=HARDWARE_PWM_FREQUENCY).contains(&self.fan.frequency) {
+            errors.push(format!(
+                "fan.frequency {} is outside 1-{}Hz for hardware PWM",
+                self.fan.frequency,
+                HARDWARE_PWM_FREQUENCY
+            ));
+        } else if !(1..=1_000_000_000).contains(&self.fan.frequency) {
+            errors.push(format!("fan.frequency {} is outside 1-1000000000Hz", self.fan.frequency));
+        }
+
+        if software && !(25..=40000).contains(&self.fan.range) {
+            errors.push(format!("fan.range {} is outside 25-40000", self.fan.range));
+        } else if !(1..=HARDWARE_PWM_RANGE).contains(&self.fan.range) {
+            errors.push(format!("fan.range {} is outside 1-{}", self.fan.range, HARDWARE_PWM_RANGE));
+        }
+
+        if !(0.0..=100.0).contains(&self.fan.min_duty) {
+            errors.push(format!("fan.min_duty {} is outside 0-100%", self.fan.min_duty));
+        }
+
+        if self.fan.kickstart > 10_000 {
+            errors.push(format!("fan.kickstart {} is above 10000ms", self.fan.kickstart));
+        }
+
+        for (name, rate) in [("ramp_up", self.fan.ramp_up), ("ramp_down", self.fan.ramp_down)].iter() {
+            if let Some(rate) = rate {
+                if !(rate.is_finite() && *rate > 0.0) {
+                    errors.push(format!("fan.{} {} must be a number > 0", name, rate));
+                }
+            }
+        }
+
+        if self.fan.ramp_tick < 10 {
+            errors.push(format!("fan.ramp_tick {} is below 10ms", self.fan.ramp_tick));
+        }
+
+        if let Some(tach) = &self.fan.tach {
+            if tach.pin > 53 {
+                errors.push(format!("fan.tach.pin {} is outside 0-53", tach.pin));
+            }
+
+            if tach.pin == self.fan.pin && pigpio {
+                errors.push(format!("fan.tach.pin {} is also fan.pin", tach.pin));
+            }
+
+            if tach.pulses_per_rev == 0 {
+                errors.push("fan.tach.pulses_per_rev must be greater than 0".to_string());
+            }
+
+            if tach.window == 0 {
+                errors.push("fan.tach.window must be at least 1 second".to_string());
+            }
+
+            if tach.source == TachSource::Pigpio && !cfg!(feature = "pigpio") {
+                errors.push("fan.tach.source \"pigpio\" is not available, build with `--features pigpio`".to_string());
+            }
+
+            if tach.source == TachSource::Script && tach.script.is_empty() {
+                errors.push("fan.tach.script must not be empty when fan.tach.source is \"script\"".to_string());
+            }
+        }
+
+        if let Some(stall) = &self.fan.stall {
+            if self.fan.tach.is_none() {
+                errors.push("fan.stall needs fan.tach to measure the fan speed".to_string());
+            }
+
+            if !(0.0..=100.0).contains(&stall.spin_threshold) {
+                errors.push(format!("fan.stall.spin_threshold {} is outside 0-100%", stall.spin_threshold));
+            }
+
+            if let Some(max_rpm) = stall.max_rpm {
+                if !(max_rpm.is_finite() && max_rpm > 0.0) {
+                    errors.push(format!("fan.stall.max_rpm {} must be a number > 0", max_rpm));
+                }
+            }
+
+            if !(0.0..=1.0).contains(&stall.min_ratio) {
+                errors.push(format!("fan.stall.min_ratio {} is outside 0-1", stall.min_ratio));
+            }
+
+            if let Some(command) = &stall.alert_command {
+                if command.trim().is_empty() {
+                    errors.push("fan.stall.alert_command must not be empty".to_string());
+                }
+            }
+        }
+
+        for problem in FanCurve::check(&self.curve.points()) {
+            errors.push(format!("curve.{}", problem));
+        }
+
+        if self.failsafe.failures == 0 {
+            errors.push("failsafe.failures must be at least 1".to_string());
+        }
+
+        if !(0.0..=100.0).contains(&self.failsafe.duty) {
+            errors.push(format!("failsafe.duty {} is outside 0-100%", self.failsafe.duty));
+        }
+
+        if !(0.0..=100.0).contains(&self.shutdown.duty) {
+            errors.push(format!("shutdown.duty {} is outside 0-100%", self.shutdown.duty));
+        }
+
+        let pid = &self.pid;
+        if !pid.setpoint.is_finite() {
+            errors.push(format!("pid.setpoint {} is not a number", pid.setpoint));
+        }
+
+        for (name, value) in [
+            ("kp", pid.kp), 
+            ("ki", pid.ki), 
+            ("kd", pid.kd), 
+            ("derivative_filter", pid.derivative_filter)
+        ].iter() {
+            if !(value.is_finite() && *value >= 0.0) {
+                errors.push(format!("pid.{} {} must be a number >= 0", name, value));
+            }
+        }
+
+        for (name, value) in [("min_duty", pid.min_duty), ("max_duty", pid.max_duty)].iter() {
+            if !(0.0..=100.0).contains(value) {
+                errors.push(format!("pid.{} {} is outside 0-100%", name, value));
+            }
+        }
+
+        if pid.min_duty > pid.max_duty {
+            errors.push(format!(
+                "pid.min_duty {} must not be above pid.max_duty {}",
+                pid.min_duty, pid.max_duty
+            ));
+        }
+
+        if let Some(hysteresis) = &self.hysteresis {
+            if !hysteresis.on.is_finite() || !hysteresis.off.is_finite() {
+                errors.push("hysteresis.on and hysteresis.off must be numbers".to_string());
+            } else if hysteresis.off > hysteresis.on {
+                errors.push(format!(
+                    "hysteresis.off {} must not be above hysteresis.on {}",
+                    hysteresis.off, hysteresis.on
+                ));
+            }
+
+            if !(0.0..=100.0).contains(&hysteresis.min_duty) {
+                errors.push(format!("hysteresis.min_duty {} is outside 0-100%", hysteresis.min_duty));
+            }
+        }
+
+        if self.history.capacity > 1_000_000 {
+            errors.push(format!("history.capacity {} is above 1000000 samples", self.history.capacity));
+        }
+
+        if self.history.file.is_some() && self.history.save_interval == 0 {
+            errors.push("history.save_interval must be at least 1 second".to_string());
+        }
+
+        if self.log.rate_limit > 0 && self.log.rate_window == 0 {
+            errors.push("log.rate_window must be at least 1 second".to_string());
+        }
+
+        if let Some(metrics) = &self.metrics {
+            if metrics.address.parse::<std::net::SocketAddr>().is_err() {
+                errors.push(format!("metrics.address {:?} is not an ip:port address", metrics.address));
+            }
+        }
+
+        if let Some(control) = &self.control {
+            if !matches!(u32::from_str_radix(&control.mode, 8), Ok(mode) if mode <= 0o777) {
+                errors.push(format!("control.mode {:?} is not an octal mode like \"0660\"", control.mode));
+            }
+
+            if let Some(group) = &control.group {
+                if group.trim().is_empty() {
+                    errors.push("control.group must not be empty".to_string());
+                }
+            }
+
+            if !control.critical_temp.is_finite() {
+                errors.push(format!("control.critical_temp {} is not a number", control.critical_temp));
+            }
+
+            if !(0.0..=100.0).contains(&control.safe_duty) {
+                errors.push(format!("control.safe_duty {} is outside 0-100%", control.safe_duty));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+}
+
+/// Parse command-line flags.
+///
+/// Returns `None` when help was requested.
+#[rustfmt::skip]
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<Overrides>> {
+    let mut overrides = Overrides::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            _ => (arg.clone(), None)
+        };
+
+        if flag == "-h" || flag == "--help" {
+            return Ok(None)
+        }
+
+        let mut value = || inline.clone()
+            .or_else(|| args.next())
+            .ok_or_else(|| anyhow!("missing value for {}", flag));
+        match flag.as_str() {
+            "-p" | "--pin" => overrides.pin = Some(parse_pin(&flag, &value()?)?),
+            "-d" | "--delay" => overrides.delay = Some(parse_delay(&flag, &value()?)?),
+            "-c" | "--config" => overrides.config = Some(PathBuf::from(value()?)),
+            "-l" | "--log-level" => overrides.log_level = Some(parse_level(&flag, &value()?)?),
+            _ => return Err(anyhow!("unknown argument {:?}\n\n{}", arg, USAGE))
+        }
+    }
+
+    Ok(Some(overrides))
+}
+
+/// Parse `RADIATOR_*` environment variables.
+#[rustfmt::skip]
+fn parse_env<V: Fn(&str) -> Result<String, env::VarError>>(lookup: V) -> Result<Overrides> {
+    let var = |key: &str| match lookup(key) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(anyhow!("{}: {}", key, e))
+    };
+
+    Ok(Overrides {
+        pin: var("RADIATOR_PIN")?.map(|v| parse_pin("RADIATOR_PIN", &v)).transpose()?,
+        delay: var("RADIATOR_DELAY")?.map(|v| parse_delay("RADIATOR_DELAY", &v)).transpose()?,
+        config: var("RADIATOR_CONFIG")?.map(PathBuf::from),
+        log_level: var("RADIATOR_LOG_LEVEL")?.map(|v| parse_level("RADIATOR_LOG_LEVEL", &v)).transpose()?
+    })
+}
+
+/// Parse a BCM GPIO number.
+#[rustfmt::skip]
+fn parse_pin(name: &str, value: &str) -> Result<u8> {
+    match value.trim().parse::<u8>() {
+        Ok(pin) if pin <= 53 => Ok(pin),
+        _ => Err(anyhow!("invalid {} {:?}: expected a GPIO number 0-53", name, value))
+    }
+}
+
+/// Parse a loop cycle in seconds.
+#[rustfmt::skip]
+fn parse_delay(name: &str, value: &str) -> Result<u64> {
+    match value.trim().parse::<u64>() {
+        Ok(delay) if delay > 0 => Ok(delay),
+        _ => Err(anyhow!("invalid {} {:?}: expected a positive number of seconds", name, value))
+    }
+}
+
+/// Parse a log level.
+#[rustfmt::skip]
+fn parse_level(name: &str, value: &str) -> Result<LogLevel> {
+    match value.trim() {
+        "error" => Ok(LogLevel::Error),
+        "warn" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        _ => Err(anyhow!("invalid {} {:?}: expected error, warn, info or debug", name, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::testutil::scratch;
+
+    /// Validation errors of a config file, one per line.
+    fn errors(text: &str) -> String {
+        match Config::parse(text).unwrap().validate() {
+            Ok(()) => String::new(),
+            Err(e) => e.to_string()
+        }
+    }
+
+    #[test]
+    fn limits_the_hardware_pwm_frequency() {
+        let daemon = "[fan]\npigpio_mode = \"daemon\"\n";
+        assert_eq!(errors(&format!("{}frequency = 125000000", daemon)), "");
+        assert!(errors(&format!("{}frequency = 125000001", daemon))
+            .contains("fan.frequency 125000001 is outside 1-125000000Hz for hardware PWM"));
+        assert!(errors(&format!("{}frequency = 1000000000", daemon)).contains("fan.frequency"));
+        assert!(errors(&format!("{}frequency = 0", daemon)).contains("fan.frequency"));
+    }
+
+    #[test]
+    fn shares_hardware_on_the_same_output() {
+        let fan = |text: &str| Config::parse(&format!("[fan]\n{}", text)).unwrap().fan;
+        let sysfs = fan("driver = \"sysfs\"\nchannel = 0");
+        assert!(sysfs.shares_hardware(&fan("driver = \"sysfs\"\nchannel = 0\nfrequency = 1000")));
+        assert!(!sysfs.shares_hardware(&fan("driver = \"sysfs\"\nchannel = 1")));
+        assert!(!sysfs.shares_hardware(&fan("driver = \"memory\"")));
+        assert!(fan("pin = 12").shares_hardware(&fan("pin = 13\npigpio_mode = \"daemon\"")));
+
+        let tach = "[fan.tach]\npin = 6";
+        assert!(fan(&format!("driver = \"memory\"\n{}", tach))
+            .shares_hardware(&fan(&format!("driver = \"sysfs\"\n{}", tach))));
+    }
+
+    #[test]
+    fn reports_every_invalid_field_at_once() {
+        let errors = errors("\
+            poll_interval = 0
+            [fan]
+            driver = \"memory\"
+            min_duty = 120.0
+            [failsafe]
+            duty = -1.0
+            [history]
+            capacity = 2000000
+            [control]
+            mode = \"rw\"");
+        let lines: Vec<&str> = errors.lines().collect();
+        assert_eq!(lines, [
+            "invalid configuration:",
+            "  poll_interval must be at least 1 second",
+            "  fan.min_duty 120 is outside 0-100%",
+            "  failsafe.duty -1 is outside 0-100%",
+            "  history.capacity 2000000 is above 1000000 samples",
+            "  control.mode \"rw\" is not an octal mode like \"0660\""
+        ]);
+    }
+
+    #[test]
+    fn leaves_sysfs_the_whole_nanosecond_range() {
+        let sysfs = "[fan]\ndriver = \"sysfs\"\n";
+        assert_eq!(errors(&format!("{}frequency = 1000000000", sysfs)), "");
+        assert!(errors(&format!("{}frequency = 1000000001", sysfs))
+            .contains("fan.frequency 1000000001 is outside 1-1000000000Hz"));
+    }
+
+    /// Configuration resolved from flags and environment variables.
+    fn resolve(args: &[&str], vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let args = args.iter().map(|arg| arg.to_string());
+        Config::resolve(args, |key| vars.get(key).cloned().ok_or(env::VarError::NotPresent))
+            .map(|config| config.unwrap())
+    }
+
+    #[test]
+    fn layers_flags_over_env_over_file_over_defaults() {
+        let dir = scratch("config-layers");
+        let defaults = dir.join("memory.toml");
+        fs::write(&defaults, "[fan]\ndriver = \"memory\"").unwrap();
+        let file = dir.join("config.toml");
+        fs::write(&file, "poll_interval = 20\n[fan]\ndriver = \"memory\"\npin = 13\n[log]\nlevel = \"warn\"").unwrap();
+        let defaults = defaults.to_str().unwrap();
+        let file = file.to_str().unwrap();
+        let layers = |config: Config| (config.fan.pin, config.poll_interval, config.log.level);
+
+        assert_eq!(layers(resolve(&["-c", defaults], &[]).unwrap()), (12, 10, LogLevel::Info));
+        assert_eq!(layers(resolve(&["-c", file], &[]).unwrap()), (13, 20, LogLevel::Warn));
+
+        let env = [
+            ("RADIATOR_CONFIG", file),
+            ("RADIATOR_PIN", "18"),
+            ("RADIATOR_DELAY", "30"),
+            ("RADIATOR_LOG_LEVEL", "error")
+        ];
+        assert_eq!(layers(resolve(&[], &env).unwrap()), (18, 30, LogLevel::Error));
+
+        let flags = ["--pin=19", "-d", "40", "--log-level", "debug"];
+        assert_eq!(layers(resolve(&flags, &env).unwrap()), (19, 40, LogLevel::Debug));
+
+        // The config file named by a flag wins over the variable too.
+        let config = resolve(&["--config", defaults], &[("RADIATOR_CONFIG", file)]).unwrap();
+        assert_eq!(layers(config), (12, 10, LogLevel::Info));
+    }
+
+    #[test]
+    fn rejects_bad_flags_and_variables() {
+        let error = |args: &[&str], vars: &[(&str, &str)]| resolve(args, vars).unwrap_err().to_string();
+        assert!(error(&["--pin", "54"], &[]).contains("invalid --pin \"54\""));
+        assert!(error(&["-d", "0"], &[]).contains("invalid -d \"0\""));
+        assert!(error(&["--log-level=loud"], &[]).contains("invalid --log-level \"loud\""));
+        assert!(error(&["--pin"], &[]).contains("missing value for --pin"));
+        assert!(error(&["--fast"], &[]).contains("unknown argument \"--fast\""));
+
+        assert!(error(&[], &[("RADIATOR_PIN", "gpio18")]).contains("invalid RADIATOR_PIN \"gpio18\""));
+        assert!(error(&[], &[("RADIATOR_DELAY", "-1")]).contains("invalid RADIATOR_DELAY \"-1\""));
+        assert!(error(&[], &[("RADIATOR_LOG_LEVEL", "")]).contains("invalid RADIATOR_LOG_LEVEL \"\""));
+
+        // A bad variable is an error even when a flag overrides it.
+        assert!(error(&["--pin", "18"], &[("RADIATOR_PIN", "x")]).contains("RADIATOR_PIN"));
+    }
+}
@@ -0,0 +1,512 @@
+use std::fs;
+use std::ffi::CString;
+use std::path::Path;
+use std::thread::spawn;
+use std::io::{
+    BufRead,
+    BufReader,
+    Write
+};
+
+use std::os::unix::fs::{
+    FileTypeExt,
+    PermissionsExt
+};
+use std::os::unix::net::{
+    UnixListener,
+    UnixStream
+};
+
+use std::sync::{
+    Arc,
+    Mutex
+};
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use serde::{
+    Deserialize,
+    Serialize
+};
+
+use serde_json::{
+    json,
+    Value
+};
+
+use super::config::{
+    Config,
+    ControlConfig
+};
+
+use super::log;
+use super::monitor::Status;
+use super::history::{
+    History,
+    downsample
+};
+use super::signal::{
+    Signal,
+    Signals
+};
+
+/// Duty(%) set by hand, instead of the controller.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Manual {
+    pub duty: f32,
+    /// When the controller takes over again, never when unset.
+    pub until: Option<Instant>
+}
+
+/// What the monitor shares with the control socket.
+#[derive(Debug, Default)]
+struct State {
+    manual: Option<Manual>,
+    status: Option<Status>,
+    config: Option<Config>,
+    history: History
+}
+
+/// Manual override and monitor state shared between the monitor
+/// and the control socket, clones share the same state.
+///
+/// #Example
+///
+/// ```
+/// let control = Control::default();
+/// control.set_manual(Some(Manual { duty: 100.0, until: None }));
+/// assert_eq!(control.manual(Instant::now()).unwrap().duty, 100.0);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Control {
+    state: Arc<Mutex<State>>
+}
+
+impl Control {
+    /// The manual duty in effect at `now`, an expired one is cleared.
+    #[rustfmt::skip]
+    pub fn manual(&self, now: Instant) -> Option<Manual> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(Manual { until: Some(until), .. }) = state.manual {
+            if now >= until {
+                info!("manual duty expired, back to automatic");
+                state.manual = None;
+            }
+        }
+
+        state.manual
+    }
+
+    /// Set or clear the manual duty.
+    pub fn set_manual(&self, manual: Option<Manual>) {
+        self.state.lock().unwrap().manual = manual;
+    }
+
+    /// Publish the monitor status.
+    pub fn set_status(&self, status: Status) {
+        self.state.lock().unwrap().status = Some(status);
+    }
+
+    /// Share the history recorded by the monitor.
+    pub fn set_history(&self, history: History) {
+        self.state.lock().unwrap().history = history;
+    }
+
+    /// Publish the active configuration.
+    pub fn set_config(&self, config: Config) {
+        self.state.lock().unwrap().config = Some(config);
+    }
+}
+
+/// One request line.
+///
+/// ```json
+/// {"cmd": "status"}
+/// {"cmd": "set", "duty": 100.0, "for": 600}
+/// {"cmd": "auto"}
+/// {"cmd": "reload"}
+/// {"cmd": "config"}
+/// {"cmd": "history", "from": 1700000000, "to": 1700086400, "points": 500}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Request {
+    /// Current monitor status.
+    Status,
+    /// Manual duty(%), for `for` secs or until `auto`.
+    Set {
+        duty: f32,
+        #[serde(rename = "for", default, skip_serializing_if = "Option::is_none")]
+        secs: Option<u64>
+    },
+    /// Back to the controller.
+    Auto,
+    /// Re-read the configuration file, like SIGHUP.
+    Reload,
+    /// Active configuration.
+    Config,
+    /// Samples from `from` to `to`(secs since the epoch), the whole
+    /// history when unset, averaged down to at most `points`.
+    History {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        points: Option<usize>
+    },
+}
+
+/// Answer a request.
+///
+/// Every answer is an object with `ok`, and either the data asked
+/// for or an `error` message.
+///
+/// #Example
+///
+/// ```
+/// let control = Control::default();
+/// let answer = handle(&control, &Signals::default(), r#"{"cmd": "auto"}"#);
+/// assert_eq!(answer["ok"], true);
+/// ```
+#[rustfmt::skip]
+pub fn handle(control: &Control, signals: &Signals, line: &str) -> Value {
+    let request = match serde_json::from_str::<Request>(line) {
+        Ok(request) => request,
+        Err(e) => return json!({ "ok": false, "error": format!("invalid request: {}", e) })
+    };
+
+    match request {
+        Request::Status => {
+            let status = control.state.lock().unwrap().status.clone();
+            json!({ "ok": true, "status": status })
+        },
+        Request::Set { duty, secs } => {
+            if !(0.0..=100.0).contains(&duty) {
+                return json!({ "ok": false, "error": format!("duty {} is outside 0-100%", duty) })
+            }
+
+            let until = match secs {
+                Some(secs) => match Instant::now().checked_add(Duration::from_secs(secs)) {
+                    Some(until) => Some(until),
+                    None => return json!({ "ok": false, "error": format!("for {}s is too long", secs) })
+                },
+                None => None
+            };
+
+            match secs {
+                Some(secs) => info!([DUTY = log::tenths(duty)], "manual duty {}% for {}s", duty, secs),
+                None => info!([DUTY = log::tenths(duty)], "manual duty {}%", duty)
+            }
+
+            control.set_manual(Some(Manual { duty, until }));
+            signals.wake();
+            json!({ "ok": true })
+        },
+        Request::Auto => {
+            info!("back to automatic control");
+            control.set_manual(None);
+            signals.wake();
+            json!({ "ok": true })
+        },
+        Request::Reload => {
+            signals.request(Signal::Hangup);
+            json!({ "ok": true })
+        },
+        Request::Config => {
+            let config = control.state.lock().unwrap().config.clone();
+            json!({ "ok": true, "config": config })
+        },
+        Request::History { from, to, points } => {
+            let history = control.state.lock().unwrap().history.clone();
+            let samples = history.range(from.unwrap_or(0), to.unwrap_or(u64::MAX));
+            let samples = match points {
+                Some(points) => downsample(&samples, points),
+                None => samples
+            };
+
+            json!({ "ok": true, "samples": samples })
+        }
+    }
+}
+
+/// Serve the control socket in the background.
+///
+/// A stale socket is replaced, any other file at the path is left
+/// alone and refused. The socket is created under a umask that only
+/// lets the owner in, then gets `mode` and, when set, `group`, so it
+/// is never reachable more widely than configured. The umask is
+/// process-wide, nothing else creates files while the service starts.
+/// Every client is served by its own thread
+/// and may send any number of requests, one JSON object per line,
+/// each answered by one line.
+///
+/// #Example
+///
+/// ```
+/// let control = Control::default();
+/// serve(&ControlConfig::default(), control.clone(), Signals::default()).unwrap();
+/// // echo '{"cmd": "status"}' | nc -U /run/radiator.sock
+/// ```
+#[rustfmt::skip]
+pub fn serve(config: &ControlConfig, control: Control, signals: Signals) -> Result<()> {
+    let path = &config.path;
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(anyhow!("{:?} exists and is not a socket, refusing to replace it", path))
+        }
+
+        fs::remove_file(path).with_context(|| format!("cannot remove stale socket {:?}", path))?;
+    }
+
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.with_context(|| format!("cannot listen on {:?}", path))?;
+    let mode = u32::from_str_radix(&config.mode, 8)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("cannot set the mode of {:?}", path))?;
+    if let Some(group) = &config.group {
+        chgrp(path, group)?;
+    }
+
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (control, signals) = (control.clone(), signals.clone());
+            spawn(move || {
+                if let Err(e) = session(stream, &control, &signals) {
+                    warn!("control client failed: {}", e);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+/// Answer the requests of one client until it hangs up.
+#[rustfmt::skip]
+fn session(stream: UnixStream, control: &Control, signals: &Signals) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+
+        let answer = handle(control, signals, &line);
+        writeln!(writer, "{}", answer)?;
+    }
+
+    Ok(())
+}
+
+/// Give a file to a group by name.
+#[rustfmt::skip]
+fn chgrp(path: &Path, group: &str) -> Result<()> {
+    let name = CString::new(group)?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow!("no group named {:?}", group))
+    }
+
+    let file = CString::new(path.to_string_lossy().as_bytes())?;
+    if unsafe { libc::chown(file.as_ptr(), u32::MAX, (*entry).gr_gid) } != 0 {
+        return Err(anyhow!(
+            "cannot give {:?} to group {:?}: {}",
+            path, group, std::io::Error::last_os_error()
+        ))
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::testutil::{
+        scratch,
+        status
+    };
+
+    /// Socket path unique to a test, nothing there yet.
+    fn socket(name: &str) -> PathBuf {
+        scratch(&format!("control-{}", name)).join("radiator.sock")
+    }
+
+    fn config(path: &Path) -> ControlConfig {
+        ControlConfig {
+            path: path.to_path_buf(),
+            mode: "0640".to_string(),
+            ..ControlConfig::default()
+        }
+    }
+
+    /// One connection to a served control socket.
+    struct Client {
+        reader: BufReader<UnixStream>,
+        writer: UnixStream
+    }
+
+    impl Client {
+        fn connect(path: &Path) -> Self {
+            let writer = UnixStream::connect(path).unwrap();
+            writer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            Self {
+                reader: BufReader::new(writer.try_clone().unwrap()),
+                writer
+            }
+        }
+
+        fn request(&mut self, line: &str) -> Value {
+            writeln!(self.writer, "{}", line).unwrap();
+            let mut answer = String::new();
+            self.reader.read_line(&mut answer).unwrap();
+            serde_json::from_str(&answer).unwrap()
+        }
+    }
+
+    /// Served socket, with the state and signals behind it.
+    fn served(name: &str) -> (Client, Control, Signals) {
+        let path = socket(name);
+        let (control, signals) = (Control::default(), Signals::default());
+        serve(&config(&path), control.clone(), signals.clone()).unwrap();
+        (Client::connect(&path), control, signals)
+    }
+
+    #[test]
+    fn answers_the_status() {
+        let (mut client, control, _) = served("status");
+        assert_eq!(client.request(r#"{"cmd": "status"}"#), json!({ "ok": true, "status": null }));
+
+        control.set_status(status());
+        let answer = client.request(r#"{"cmd": "status"}"#);
+        assert_eq!(answer["ok"], true);
+        assert_eq!(answer["status"]["temp"], 52.5);
+        assert_eq!(answer["status"]["duty"], 60.0);
+        assert_eq!(answer["status"]["manual"], Value::Null);
+    }
+
+    #[test]
+    fn sets_a_manual_duty_for_a_while_then_back_to_auto() {
+        let (mut client, control, signals) = served("set");
+        let before = Instant::now();
+        assert_eq!(client.request(r#"{"cmd": "set", "duty": 80, "for": 600}"#), json!({ "ok": true }));
+        let manual = control.manual(Instant::now()).unwrap();
+        assert_eq!(manual.duty, 80.0);
+        let until = manual.until.unwrap();
+        assert!(until >= before + Duration::from_secs(600));
+        assert!(until <= Instant::now() + Duration::from_secs(600));
+        // the monitor is woken up to apply it at once.
+        assert!(signals.wait(Duration::from_secs(5)));
+
+        // it expires by itself.
+        assert_eq!(control.manual(until), None);
+
+        // radiatorctl sends a null `for` without --for.
+        assert_eq!(client.request(r#"{"cmd": "set", "duty": 100, "for": null}"#), json!({ "ok": true }));
+        assert_eq!(control.manual(Instant::now()), Some(Manual { duty: 100.0, until: None }));
+        assert_eq!(client.request(r#"{"cmd": "auto"}"#), json!({ "ok": true }));
+        assert_eq!(control.manual(Instant::now()), None);
+    }
+
+    #[test]
+    fn refuses_a_duty_out_of_range() {
+        let (mut client, control, _) = served("range");
+        let answer = client.request(r#"{"cmd": "set", "duty": 150}"#);
+        assert_eq!(answer, json!({ "ok": false, "error": "duty 150 is outside 0-100%" }));
+        assert_eq!(control.manual(Instant::now()), None);
+    }
+
+    #[test]
+    fn requests_a_reload() {
+        let (mut client, _, signals) = served("reload");
+        assert!(!signals.take_reload());
+        assert_eq!(client.request(r#"{"cmd": "reload"}"#), json!({ "ok": true }));
+        assert!(signals.take_reload());
+        assert_eq!(signals.stop(), None);
+    }
+
+    #[test]
+    fn answers_the_config() {
+        let (mut client, control, _) = served("config");
+        control.set_config(Config { poll_interval: 5, ..Config::default() });
+        let answer = client.request(r#"{"cmd": "config"}"#);
+        assert_eq!(answer["ok"], true);
+        assert_eq!(answer["config"]["poll_interval"], 5);
+        assert_eq!(answer["config"]["curve"]["points"], json!([[40.0, 0.0], [60.0, 100.0]]));
+    }
+
+    #[test]
+    fn answers_the_history() {
+        let (mut client, control, _) = served("history");
+        let history = History::new(10);
+        for time in 1..=4 {
+            history.record(crate::history::Sample { time, temp: Some(time as f32), duty: 50.0, rpm: None });
+        }
+
+        control.set_history(history);
+        let answer = client.request(r#"{"cmd": "history", "from": 2, "points": 2}"#);
+        assert_eq!(answer["samples"], json!([
+            { "time": 2, "temp": 2.5, "duty": 50.0, "rpm": null },
+            { "time": 4, "temp": 4.0, "duty": 50.0, "rpm": null }
+        ]));
+    }
+
+    #[test]
+    fn keeps_the_connection_after_a_bad_request() {
+        let (mut client, _, _) = served("bad");
+        let answer = client.request("{\"cmd\": \"fly\"}");
+        assert_eq!(answer["ok"], false);
+        assert!(answer["error"].as_str().unwrap().starts_with("invalid request: "));
+        assert_eq!(client.request("not json")["ok"], false);
+        // blank lines get no answer, the next request does.
+        writeln!(client.writer).unwrap();
+        assert_eq!(client.request(r#"{"cmd": "auto"}"#), json!({ "ok": true }));
+    }
+
+    #[test]
+    fn refuses_a_duration_past_the_clock() {
+        let control = Control::default();
+        let answer = handle(&control, &Signals::default(), &format!(r#"{{"cmd": "set", "duty": 50, "for": {}}}"#, u64::MAX));
+        assert_eq!(answer["ok"], false);
+        assert_eq!(answer["error"], format!("for {}s is too long", u64::MAX));
+        assert_eq!(control.manual(Instant::now()), None);
+    }
+
+    #[test]
+    fn creates_the_socket_with_its_mode() {
+        let path = socket("mode");
+        serve(&config(&path), Control::default(), Signals::default()).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn replaces_a_stale_socket() {
+        let path = socket("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        serve(&config(&path), Control::default(), Signals::default()).unwrap();
+        assert!(UnixStream::connect(&path).is_ok());
+    }
+
+    #[test]
+    fn leaves_other_files_alone() {
+        let path = socket("file");
+        fs::write(&path, "keep me").unwrap();
+        let e = serve(&config(&path), Control::default(), Signals::default()).unwrap_err();
+        assert!(e.to_string().contains("is not a socket"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+    }
+}
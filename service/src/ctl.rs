@@ -0,0 +1,706 @@
+use std::env;
+use std::fs;
+use std::path::{
+    Path,
+    PathBuf
+};
+use std::thread::sleep;
+use std::time::{
+    Duration,
+    SystemTime,
+    UNIX_EPOCH
+};
+use std::os::unix::net::UnixStream;
+use std::io::{
+    BufRead,
+    BufReader,
+    Write
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use serde_json::{
+    json,
+    Value
+};
+
+/// Control socket of the service.
+const SOCKET_PATH: &str = "/run/radiator.sock";
+
+const USAGE: &str = "\
+Usage: radiatorctl [OPTIONS] <COMMAND>
+
+Commands:
+    status [--json]                  print the fan status
+    set <PERCENT> [--for <TIME>]     set the duty by hand, e.g. --for 10m
+    auto                             back to automatic control
+    reload                           re-read the configuration file
+    curve show                       print the active curve or PID settings
+    watch [--interval <TIME>]        live-update temperature, duty and rpm
+    history [--since <TIME>] [--until <TIME>] [--points <N>] [--json] [--output <PATH>]
+                                     export the samples taken from --since ago (default 1h)
+                                     to --until ago (default now) as CSV or JSON, averaged
+                                     down to at most N points
+
+Options:
+    -s, --socket <PATH>    control socket (env: RADIATOR_SOCKET, default: /run/radiator.sock)
+    -h, --help             print this help
+
+TIME is a number of seconds or ends in s, m, h or d.";
+
+/// What to ask the service.
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Status { json: bool },
+    Set { duty: f32, secs: Option<u64> },
+    Auto,
+    Reload,
+    CurveShow,
+    Watch { interval: Duration },
+    History { since: u64, until: u64, points: Option<usize>, json: bool, output: Option<PathBuf> },
+}
+
+/// Line-delimited JSON client of the control socket.
+///
+/// #Example
+///
+/// ```
+/// let mut client = Client::connect(Path::new("/run/radiator.sock")).unwrap();
+/// let answer = client.request(&json!({ "cmd": "status" })).unwrap();
+/// println!("{}", answer["status"]);
+/// ```
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream
+}
+
+impl Client {
+    #[rustfmt::skip]
+    fn connect(path: &Path) -> Result<Self> {
+        let writer = UnixStream::connect(path)
+            .with_context(|| format!("cannot connect to {:?}, is [control] enabled?", path))?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer
+        })
+    }
+
+    /// Send one request, returns the answer or the error it carries.
+    #[rustfmt::skip]
+    fn request(&mut self, request: &Value) -> Result<Value> {
+        writeln!(self.writer, "{}", request)?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(anyhow!("the service closed the connection"))
+        }
+
+        let answer: Value = serde_json::from_str(&line)
+            .context("invalid answer from the service")?;
+        if answer["ok"] != true {
+            return Err(anyhow!("{}", answer["error"].as_str().unwrap_or("request failed")))
+        }
+
+        Ok(answer)
+    }
+}
+
+/// Parse a duration, seconds or a number ending in `s`, `m`, `h` or `d`.
+///
+/// #Example
+///
+/// ```
+/// assert_eq!(parse_duration("10m").unwrap(), 600);
+/// assert_eq!(parse_duration("90").unwrap(), 90);
+/// ```
+#[rustfmt::skip]
+fn parse_duration(value: &str) -> Result<u64> {
+    let (number, unit) = match value.char_indices().last() {
+        Some((i, 's')) => (&value[..i], 1),
+        Some((i, 'm')) => (&value[..i], 60),
+        Some((i, 'h')) => (&value[..i], 3600),
+        Some((i, 'd')) => (&value[..i], 86400),
+        _ => (value, 1)
+    };
+
+    number.parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit))
+        .ok_or_else(|| anyhow!("invalid duration {:?}, expected e.g. 30s, 10m, 1h or 7d", value))
+}
+
+/// Format seconds, e.g. `9m 58s`.
+#[rustfmt::skip]
+fn format_duration(secs: u64) -> String {
+    match (secs / 3600, secs % 3600 / 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, _) => format!("{}h {}m", h, m)
+    }
+}
+
+/// Parse the command line.
+///
+/// Returns `None` when help was requested.
+#[rustfmt::skip]
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Option<(PathBuf, Command)>> {
+    let mut socket = env::var("RADIATOR_SOCKET")
+        .ok()
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(SOCKET_PATH));
+    let mut words = Vec::new();
+    let mut json = false;
+    let mut secs = None;
+    let mut interval = Duration::from_secs(1);
+    let mut since = 3600;
+    let mut until = 0;
+    let mut points = None;
+    let mut output = None;
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            _ => (arg.clone(), None)
+        };
+
+        if flag == "-h" || flag == "--help" {
+            return Ok(None)
+        }
+
+        let mut value = || inline.clone()
+            .or_else(|| args.next())
+            .ok_or_else(|| anyhow!("missing value for {}", flag));
+        match flag.as_str() {
+            "-s" | "--socket" => socket = PathBuf::from(value()?),
+            "--json" => json = true,
+            "--for" => secs = Some(parse_duration(&value()?)?),
+            "--since" => since = parse_duration(&value()?)?,
+            "--until" => until = parse_duration(&value()?)?,
+            "--points" => match value()?.parse::<usize>() {
+                Ok(n) if n > 0 => points = Some(n),
+                _ => return Err(anyhow!("--points must be a positive number"))
+            },
+            "--output" => output = Some(PathBuf::from(value()?)),
+            "--interval" => match parse_duration(&value()?)? {
+                0 => return Err(anyhow!("--interval must be at least 1s")),
+                n => interval = Duration::from_secs(n)
+            },
+            _ if flag.starts_with('-') && flag.parse::<f32>().is_err() => {
+                return Err(anyhow!("unknown argument {:?}\n\n{}", arg, USAGE))
+            },
+            _ => words.push(arg)
+        }
+    }
+
+    let words = words.iter().map(String::as_str).collect::<Vec<_>>();
+    let command = match words.as_slice() {
+        ["status"] => Command::Status { json },
+        ["set", duty] => Command::Set {
+            duty: duty.trim_end_matches('%')
+                .parse()
+                .map_err(|_| anyhow!("invalid duty {:?}, expected a percentage", duty))?,
+            secs
+        },
+        ["auto"] => Command::Auto,
+        ["reload"] => Command::Reload,
+        ["curve", "show"] => Command::CurveShow,
+        ["watch"] => Command::Watch { interval },
+        ["history"] if until <= since => Command::History { since, until, points, json, output },
+        ["history"] => return Err(anyhow!("--until must not be before --since")),
+        [] => return Err(anyhow!("missing command\n\n{}", USAGE)),
+        _ => return Err(anyhow!("unknown command {:?}\n\n{}", words.join(" "), USAGE))
+    };
+
+    Ok(Some((socket, command)))
+}
+
+/// Human-readable status, one field per line.
+#[rustfmt::skip]
+fn format_status(status: &Value) -> String {
+    if status.is_null() {
+        return "no status yet, the service has not polled".to_string()
+    }
+
+    let temp = match status["temp"].as_f64() {
+        Some(temp) => format!("{:.1}°C", temp),
+        None => "unavailable".to_string()
+    };
+
+    let rpm = match status["rpm"].as_f64() {
+        Some(rpm) => format!("{:.0}rpm", rpm),
+        None => "no tachometer".to_string()
+    };
+
+    let mode = match (status["manual"].as_f64(), status["manual_expires"].as_u64()) {
+        (Some(duty), Some(secs)) => format!("manual {:.0}%, {} left", duty, format_duration(secs)),
+        (Some(duty), None) => format!("manual {:.0}%", duty),
+        _ => "automatic".to_string()
+    };
+
+    let fan = if status["fan_failed"] == true { "FAILED" } else { "ok" };
+    format!(
+        "temperature:     {}\nduty:            {:.0}%\nfan speed:       {}\nmode:            {}\nfan:             {}\nsensor failures: {}",
+        temp,
+        status["duty"].as_f64().unwrap_or(0.0),
+        rpm,
+        mode,
+        fan,
+        status["sensor_failures"].as_u64().unwrap_or(0)
+    )
+}
+
+/// The status on a single line, for `watch`.
+#[rustfmt::skip]
+fn format_line(status: &Value) -> String {
+    if status.is_null() {
+        return "waiting for the first poll".to_string()
+    }
+
+    let mut line = match status["temp"].as_f64() {
+        Some(temp) => format!("{:5.1}°C", temp),
+        None => "   --°C".to_string()
+    };
+
+    line += &format!("  duty {:3.0}%", status["duty"].as_f64().unwrap_or(0.0));
+    if let Some(rpm) = status["rpm"].as_f64() {
+        line += &format!("  {:5.0}rpm", rpm);
+    }
+
+    if let Some(duty) = status["manual"].as_f64() {
+        line += &format!("  manual {:.0}%", duty);
+    }
+
+    if status["fan_failed"] == true {
+        line += "  FAN FAILED";
+    }
+
+    line
+}
+
+/// The active curve or PID settings.
+#[rustfmt::skip]
+fn format_curve(config: &Value) -> String {
+    let mut text = String::new();
+    if config["mode"] == "pid" {
+        let pid = &config["pid"];
+        text += &format!("mode:     pid\nsetpoint: {}°C\n", pid["setpoint"]);
+        text += &format!("kp:       {}\nki:       {}\nkd:       {}\n", pid["kp"], pid["ki"], pid["kd"]);
+        text += &format!("duty:     {}-{}%", pid["min_duty"], pid["max_duty"]);
+        return text
+    }
+
+    text += "mode: curve\n";
+    for point in config["curve"]["points"].as_array().into_iter().flatten() {
+        text += &format!(
+            "  {:5.1}°C -> {:3.0}%\n",
+            point[0].as_f64().unwrap_or(0.0),
+            point[1].as_f64().unwrap_or(0.0)
+        );
+    }
+
+    if let Some(hysteresis) = config["hysteresis"].as_object() {
+        text += &format!("fan on above {}°C, off below {}°C\n", hysteresis["on"], hysteresis["off"]);
+    }
+
+    text.trim_end().to_string()
+}
+
+/// Seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// A number rounded to a tenth, empty when missing.
+fn tenths(value: &Value) -> Option<f64> {
+    value.as_f64().map(|value| (value * 10.0).round() / 10.0)
+}
+
+/// Samples as CSV, one row per sample with the time in secs
+/// since the epoch, a missing value left empty.
+#[rustfmt::skip]
+fn format_csv(samples: &[Value]) -> String {
+    let cell = |value: &Value| tenths(value).map(|v| v.to_string()).unwrap_or_default();
+    let mut text = "time,temp_c,duty,rpm\n".to_string();
+    for sample in samples {
+        text += &format!(
+            "{},{},{},{}\n",
+            sample["time"],
+            cell(&sample["temp"]),
+            cell(&sample["duty"]),
+            cell(&sample["rpm"])
+        );
+    }
+
+    text
+}
+
+/// Samples as a JSON array.
+#[rustfmt::skip]
+fn format_json(samples: &[Value]) -> Result<String> {
+    let samples = samples.iter()
+        .map(|sample| json!({
+            "time": sample["time"],
+            "temp": tenths(&sample["temp"]),
+            "duty": tenths(&sample["duty"]),
+            "rpm": tenths(&sample["rpm"])
+        }))
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_string_pretty(&samples)? + "\n")
+}
+
+/// Run a command against the service.
+#[rustfmt::skip]
+fn run(client: &mut Client, command: Command) -> Result<()> {
+    match command {
+        Command::Status { json } => {
+            let answer = client.request(&json!({ "cmd": "status" }))?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&answer["status"])?);
+            } else {
+                println!("{}", format_status(&answer["status"]));
+            }
+        },
+        Command::Set { duty, secs } => {
+            client.request(&json!({ "cmd": "set", "duty": duty, "for": secs }))?;
+            match secs {
+                Some(secs) => println!("duty set to {}% for {}", duty, format_duration(secs)),
+                None => println!("duty set to {}% until `radiatorctl auto`", duty)
+            }
+        },
+        Command::Auto => {
+            client.request(&json!({ "cmd": "auto" }))?;
+            println!("back to automatic control");
+        },
+        Command::Reload => {
+            client.request(&json!({ "cmd": "reload" }))?;
+            println!("reload requested, see the service log for the result");
+        },
+        Command::CurveShow => {
+            let answer = client.request(&json!({ "cmd": "config" }))?;
+            println!("{}", format_curve(&answer["config"]));
+        },
+        Command::History { since, until, points, json, output } => {
+            let now = now();
+            let request = json!({
+                "cmd": "history",
+                "from": now.saturating_sub(since),
+                "to": now.saturating_sub(until),
+                "points": points
+            });
+
+            let answer = client.request(&request)?;
+            let samples = answer["samples"].as_array().cloned().unwrap_or_default();
+            let text = if json { format_json(&samples)? } else { format_csv(&samples) };
+            match output {
+                Some(path) => {
+                    fs::write(&path, text).with_context(|| format!("cannot write {:?}", path))?;
+                    eprintln!("{} samples written to {:?}", samples.len(), path);
+                },
+                None => print!("{}", text)
+            }
+        },
+        Command::Watch { interval } => {
+            let tty = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+            let mut stdout = std::io::stdout();
+            loop {
+                let answer = client.request(&json!({ "cmd": "status" }))?;
+                let line = format_line(&answer["status"]);
+                if tty {
+                    write!(stdout, "\r\x1b[K{}", line)?;
+                } else {
+                    writeln!(stdout, "{}", line)?;
+                }
+
+                stdout.flush()?;
+                sleep(interval);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse the command line and run the command against the service.
+#[rustfmt::skip]
+pub fn main() -> Result<()> {
+    let (socket, command) = match parse_args(env::args().skip(1))? {
+        Some(parsed) => parsed,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let mut client = Client::connect(&socket)?;
+    run(&mut client, command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::control::{
+        self,
+        Control,
+        Manual
+    };
+    use crate::config::{
+        Config,
+        ControlConfig
+    };
+    use crate::history::{
+        History,
+        Sample
+    };
+    use crate::monitor::Status;
+    use crate::testutil::{
+        scratch,
+        status
+    };
+    use crate::signal::Signals;
+
+    fn args(line: &str) -> Result<Option<(PathBuf, Command)>> {
+        parse_args(line.split_whitespace().map(str::to_string))
+    }
+
+    /// Command parsed from a command line with an explicit socket.
+    fn command(line: &str) -> Command {
+        args(&format!("-s /tmp/test.sock {}", line)).unwrap().unwrap().1
+    }
+
+    fn error(line: &str) -> String {
+        args(&format!("-s /tmp/test.sock {}", line)).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("30s").unwrap(), 30);
+        assert_eq!(parse_duration("10m").unwrap(), 600);
+        assert_eq!(parse_duration("2h").unwrap(), 7200);
+        assert_eq!(parse_duration("7d").unwrap(), 604800);
+        assert_eq!(parse_duration("0").unwrap(), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        for value in &["", "m", "10x", "-5s", "1.5h", "10 m", "99999999999999999999", "300000000000000d"] {
+            assert!(parse_duration(value).is_err(), "{:?} parsed", value);
+        }
+
+        assert_eq!(
+            parse_duration("soon").unwrap_err().to_string(),
+            "invalid duration \"soon\", expected e.g. 30s, 10m, 1h or 7d"
+        );
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(598), "9m 58s");
+        assert_eq!(format_duration(7320), "2h 2m");
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(command("status"), Command::Status { json: false });
+        assert_eq!(command("status --json"), Command::Status { json: true });
+        assert_eq!(command("set 80"), Command::Set { duty: 80.0, secs: None });
+        assert_eq!(command("set 80% --for 10m"), Command::Set { duty: 80.0, secs: Some(600) });
+        assert_eq!(command("--for=90 set 42.5"), Command::Set { duty: 42.5, secs: Some(90) });
+        assert_eq!(command("auto"), Command::Auto);
+        assert_eq!(command("reload"), Command::Reload);
+        assert_eq!(command("curve show"), Command::CurveShow);
+        assert_eq!(command("watch"), Command::Watch { interval: Duration::from_secs(1) });
+        assert_eq!(command("watch --interval 5s"), Command::Watch { interval: Duration::from_secs(5) });
+        assert_eq!(
+            command("history"),
+            Command::History { since: 3600, until: 0, points: None, json: false, output: None }
+        );
+        assert_eq!(
+            command("history --since 1d --until=1h --points 100 --json --output /tmp/h.json"),
+            Command::History {
+                since: 86400,
+                until: 3600,
+                points: Some(100),
+                json: true,
+                output: Some(PathBuf::from("/tmp/h.json"))
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_command_lines() {
+        assert!(error("").starts_with("missing command"));
+        assert!(error("spin").starts_with("unknown command \"spin\""));
+        assert!(error("status --verbose").starts_with("unknown argument \"--verbose\""));
+        assert_eq!(error("set fast"), "invalid duty \"fast\", expected a percentage");
+        assert_eq!(error("set 50 --for"), "missing value for --for");
+        assert_eq!(error("watch --interval 0"), "--interval must be at least 1s");
+        assert_eq!(error("history --points 0"), "--points must be a positive number");
+        assert_eq!(error("history --since 1h --until 2h"), "--until must not be before --since");
+    }
+
+    #[test]
+    fn asks_for_help() {
+        assert!(args("--help").unwrap().is_none());
+        assert!(args("status -h").unwrap().is_none());
+    }
+
+    #[test]
+    fn picks_the_socket() {
+        // the only test touching the variable.
+        env::set_var("RADIATOR_SOCKET", "/tmp/env.sock");
+        let from_env = args("status").unwrap().unwrap().0;
+        let from_flag = args("--socket=/tmp/flag.sock status").unwrap().unwrap().0;
+        env::remove_var("RADIATOR_SOCKET");
+        let default = args("status").unwrap().unwrap().0;
+
+        assert_eq!(from_env, PathBuf::from("/tmp/env.sock"));
+        assert_eq!(from_flag, PathBuf::from("/tmp/flag.sock"));
+        assert_eq!(default, PathBuf::from(SOCKET_PATH));
+    }
+
+    #[test]
+    fn formats_the_status() {
+        let status = json!({
+            "temp": 52.34,
+            "duty": 60.4,
+            "rpm": 1810.6,
+            "fan_failed": false,
+            "sensor_failures": 0,
+            "manual": 80.0,
+            "manual_expires": 598
+        });
+        assert_eq!(format_status(&status), "\
+temperature:     52.3°C
+duty:            60%
+fan speed:       1811rpm
+mode:            manual 80%, 9m 58s left
+fan:             ok
+sensor failures: 0");
+    }
+
+    #[test]
+    fn formats_a_degraded_status() {
+        let status = json!({
+            "temp": null,
+            "duty": 100.0,
+            "rpm": null,
+            "fan_failed": true,
+            "sensor_failures": 3,
+            "manual": null,
+            "manual_expires": null
+        });
+        assert_eq!(format_status(&status), "\
+temperature:     unavailable
+duty:            100%
+fan speed:       no tachometer
+mode:            automatic
+fan:             FAILED
+sensor failures: 3");
+        assert_eq!(format_status(&Value::Null), "no status yet, the service has not polled");
+
+        let manual = json!({ "temp": 45.0, "duty": 100.0, "manual": 100.0, "manual_expires": null });
+        assert!(format_status(&manual).contains("mode:            manual 100%\n"));
+    }
+
+    /// Client of a real control socket, with the state and signals
+    /// behind it.
+    fn served(name: &str) -> (Client, Control, Signals) {
+        let path = scratch(&format!("ctl-{}", name)).join("radiator.sock");
+        let config = ControlConfig {
+            path: path.clone(),
+            ..ControlConfig::default()
+        };
+
+        let (control, signals) = (Control::default(), Signals::default());
+        control::serve(&config, control.clone(), signals.clone()).unwrap();
+        (Client::connect(&path).unwrap(), control, signals)
+    }
+
+    #[test]
+    fn sets_a_duty_for_a_while_and_back_to_auto() {
+        let (mut client, control, _) = served("set");
+        run(&mut client, command("set 80% --for 10m")).unwrap();
+        let manual = control.manual(Instant::now()).unwrap();
+        assert_eq!(manual.duty, 80.0);
+        assert!(manual.until.unwrap() > Instant::now() + Duration::from_secs(590));
+
+        run(&mut client, command("set 100")).unwrap();
+        assert_eq!(control.manual(Instant::now()).unwrap().until, None);
+        run(&mut client, command("auto")).unwrap();
+        assert_eq!(control.manual(Instant::now()), None);
+    }
+
+    #[test]
+    fn carries_the_service_errors() {
+        let (mut client, control, _) = served("error");
+        let e = run(&mut client, command("set 150")).unwrap_err();
+        assert_eq!(e.to_string(), "duty 150 is outside 0-100%");
+        assert_eq!(control.manual(Instant::now()), None);
+    }
+
+    #[test]
+    fn requests_a_reload() {
+        let (mut client, _, signals) = served("reload");
+        run(&mut client, command("reload")).unwrap();
+        assert!(signals.take_reload());
+    }
+
+    #[test]
+    fn reads_the_status_the_monitor_published() {
+        let (mut client, control, _) = served("status");
+        let answer = client.request(&json!({ "cmd": "status" })).unwrap();
+        assert_eq!(format_status(&answer["status"]), "no status yet, the service has not polled");
+
+        control.set_manual(Some(Manual { duty: 80.0, until: None }));
+        control.set_status(Status {
+            duty: 80.0,
+            manual: Some(80.0),
+            ..status()
+        });
+        let answer = client.request(&json!({ "cmd": "status" })).unwrap();
+        assert!(format_status(&answer["status"]).starts_with("temperature:     52.5°C\nduty:            80%\n"));
+        assert!(format_line(&answer["status"]).ends_with("manual 80%"));
+        run(&mut client, command("status --json")).unwrap();
+    }
+
+    #[test]
+    fn shows_the_active_curve() {
+        let (mut client, control, _) = served("curve");
+        control.set_config(Config::parse("[hysteresis]\non = 50\noff = 45").unwrap());
+
+        let answer = client.request(&json!({ "cmd": "config" })).unwrap();
+        assert_eq!(format_curve(&answer["config"]), "\
+mode: curve
+   40.0°C ->   0%
+   60.0°C -> 100%
+fan on above 50.0°C, off below 45.0°C");
+        run(&mut client, command("curve show")).unwrap();
+    }
+
+    #[test]
+    fn exports_the_history() {
+        let (mut client, control, _) = served("history");
+        let history = History::new(10);
+        let now = now();
+        history.record(Sample { time: now - 20, temp: Some(45.04), duty: 25.0, rpm: None });
+        history.record(Sample { time: now - 10, temp: None, duty: 100.0, rpm: Some(1810.0) });
+        history.record(Sample { time: now - 7200, temp: Some(40.0), duty: 0.0, rpm: None });
+        control.set_history(history);
+
+        let output = scratch("ctl-export").join("history.csv");
+        run(&mut client, command(&format!("history --output {}", output.display()))).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), format!(
+            "time,temp_c,duty,rpm\n{},45,25,\n{},,100,1810\n",
+            now - 20, now - 10
+        ));
+    }
+}
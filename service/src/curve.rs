@@ -0,0 +1,237 @@
+use anyhow::{
+    Result,
+    anyhow
+};
+
+use super::config::BelowMin;
+
+/// Piecewise-linear fan curve.
+///
+/// Built from `(temperature(°C), duty(%))` points in ascending
+/// temperature, the duty never goes down. Between two points the
+/// duty is linearly interpolated, below the first point and above
+/// the last point it is held at their duty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanCurve {
+    points: Vec<(f32, f32)>
+}
+
+impl FanCurve {
+    /// Create a curve from ordered points.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// // quiet zone, steep middle and plateau.
+    /// let curve = FanCurve::new(vec![
+    ///     (45.0, 0.0),
+    ///     (50.0, 30.0),
+    ///     (55.0, 90.0),
+    ///     (70.0, 100.0)
+    /// ]).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(points: Vec<(f32, f32)>) -> Result<Self> {
+        let problems = Self::check(&points);
+        if !problems.is_empty() {
+            return Err(anyhow!("invalid fan curve: {}", problems.join(", ")))
+        }
+
+        Ok(Self {
+            points
+        })
+    }
+
+    /// List every problem of the points, prefixed with
+    /// `points` or `points[index]`.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let problems = FanCurve::check(&[(60.0, 0.0), (40.0, 100.0)]);
+    /// // points[1] temperature 40 must be above the previous point 60
+    /// ```
+    #[rustfmt::skip]
+    pub fn check(points: &[(f32, f32)]) -> Vec<String> {
+        let mut problems = Vec::new();
+        if points.len() < 2 {
+            problems.push(format!("points needs at least 2 points, got {}", points.len()));
+        }
+
+        for (i, (temp, duty)) in points.iter().enumerate() {
+            if !temp.is_finite() {
+                problems.push(format!("points[{}] temperature {} is not a number", i, temp));
+            }
+
+            if !(0.0..=100.0).contains(duty) {
+                problems.push(format!("points[{}] duty {} is outside 0-100%", i, duty));
+            }
+
+            if let Some((prev_temp, prev_duty)) = i.checked_sub(1).map(|p| points[p]) {
+                if *temp <= prev_temp {
+                    problems.push(format!(
+                        "points[{}] temperature {} must be above the previous point {}",
+                        i, temp, prev_temp
+                    ));
+                }
+
+                if *duty < prev_duty {
+                    problems.push(format!(
+                        "points[{}] duty {} must not be below the previous point {}",
+                        i, duty, prev_duty
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Compute duty(%) for the temperature.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let curve = FanCurve::default();
+    /// assert_eq!(curve.duty(50.0), 50.0);
+    /// ```
+    #[rustfmt::skip]
+    pub fn duty(&self, temp: f32) -> f32 {
+        let points = &self.points;
+        match points.iter().position(|(t, _)| temp < *t) {
+            Some(0) => points[0].1,
+            None => points[points.len() - 1].1,
+            Some(i) => {
+                let (t0, d0) = points[i - 1];
+                let (t1, d1) = points[i];
+                d0 + (temp - t0) * (d1 - d0) / (t1 - t0)
+            }
+        }
+    }
+}
+
+impl Default for FanCurve {
+    /// Ramp from off at 40°C to fully on at 60°C.
+    ///
+    /// All Raspberry Pi models perform a degree of thermal management
+    /// to avoid overheating under heavy load. The SoCs have an internal
+    /// temperature sensor, which software on the GPU polls to ensure that
+    /// temperatures do not exceed a predefined limit; this is 85°C on
+    /// all models. It is possible to set this to a lower value, but not
+    /// to a higher one. As the device approaches the limit, various
+    /// frequencies and sometimes voltages used on the chip (ARM, GPU) are
+    /// reduced. This reduces the amount of heat generated, keeping
+    /// the temperature under control.
+    ///
+    /// When the core temperature is between 80°C and 85°C, a warning icon
+    /// showing a red half-filled thermometer will be displayed, and the
+    /// ARM cores will be progressively throttled back. If the temperature
+    /// reaches 85°C, an icon showing a fully filled thermometer will be
+    /// displayed, and both the ARM cores and the GPU will be throttled back.
+    /// See the page on warning icons for images of the icons.
+    ///
+    /// For Raspberry Pi 3 Model B+, the PCB technology has been changed to
+    /// provide better heat dissipation and increased thermal mass. In addition,
+    /// a soft temperature limit has been introduced, with the goal of
+    /// maximising the time for which a device can "sprint" before reaching
+    /// the hard limit at 85°C. When the soft limit is reached, the clock
+    /// speed is reduced from 1.4GHz to 1.2GHz, and the operating voltage is
+    /// reduced slightly. This reduces the rate of temperature increase:
+    /// we trade a short period at 1.4GHz for a longer period at 1.2GHz.
+    /// By default, the soft limit is 60°C.
+    ///
+    /// The Raspberry Pi 4 Model B continues with the same PCB technology
+    /// as the Raspberry Pi 3B+ to help dissipate excess heat.
+    /// There is currently no soft limit defined.
+    fn default() -> Self {
+        Self {
+            points: vec![(40.0, 0.0), (60.0, 100.0)]
+        }
+    }
+}
+
+/// Keep a running fan at or above its minimum duty(%).
+///
+/// A duty between 0 and `min` would leave the fan stalled while
+/// it still draws current, so it is either switched off or raised
+/// to `min`. A duty of 0 stays 0.
+///
+/// #Example
+///
+/// ```
+/// assert_eq!(min_running(5.0, 30.0, BelowMin::Off), 0.0);
+/// assert_eq!(min_running(5.0, 30.0, BelowMin::Min), 30.0);
+/// assert_eq!(min_running(45.0, 30.0, BelowMin::Off), 45.0);
+/// ```
+#[rustfmt::skip]
+pub fn min_running(duty: f32, min: f32, below: BelowMin) -> f32 {
+    if duty <= 0.0 || duty >= min {
+        return duty
+    }
+
+    match below {
+        BelowMin::Off => 0.0,
+        BelowMin::Min => min
+    }
+}
+
+/// Scale duty(%) up to the PWM range, rounding up so that
+/// any non-zero duty turns the fan on.
+///
+/// #Example
+///
+/// ```
+/// assert_eq!(to_pwm(5.0, 255), 13);
+/// ```
+pub fn to_pwm(duty: f32, range: u32) -> u32 {
+    (duty * range as f32 / 100.0).ceil() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The hard-coded ramp the default curve replaced.
+    fn get_pwm(temp: f32) -> u8 {
+        if temp <= 40.0 { return 0 }
+        if temp >= 60.0 { return 255 }
+        ((temp - 40.0) * 12.75).ceil() as u8
+    }
+
+    #[test]
+    fn default_curve_matches_get_pwm() {
+        let curve = FanCurve::default();
+        for tenth in 300..=700 {
+            let temp = tenth as f32 / 10.0;
+            assert_eq!(to_pwm(curve.duty(temp), 255), u32::from(get_pwm(temp)), "at {}°C", temp);
+        }
+    }
+
+    #[test]
+    fn interpolates_and_holds_the_ends() {
+        let curve = FanCurve::new(vec![(45.0, 0.0), (50.0, 30.0), (55.0, 90.0), (70.0, 100.0)]).unwrap();
+        assert_eq!(curve.duty(30.0), 0.0);
+        assert_eq!(curve.duty(47.5), 15.0);
+        assert_eq!(curve.duty(52.5), 60.0);
+        assert_eq!(curve.duty(80.0), 100.0);
+    }
+
+    #[test]
+    fn rejects_unordered_and_falling_points() {
+        assert!(FanCurve::new(vec![(60.0, 0.0), (40.0, 100.0)]).is_err());
+        assert!(FanCurve::new(vec![(40.0, 50.0), (60.0, 20.0)]).is_err());
+        assert!(FanCurve::new(vec![(40.0, 50.0)]).is_err());
+        assert!(FanCurve::new(vec![(40.0, 0.0), (60.0, 120.0)]).is_err());
+    }
+
+    #[test]
+    fn stops_or_holds_the_minimum_below_it() {
+        assert_eq!(min_running(0.0, 30.0, BelowMin::Off), 0.0);
+        assert_eq!(min_running(0.0, 30.0, BelowMin::Min), 0.0);
+        assert_eq!(min_running(29.9, 30.0, BelowMin::Off), 0.0);
+        assert_eq!(min_running(0.1, 30.0, BelowMin::Min), 30.0);
+        assert_eq!(min_running(30.0, 30.0, BelowMin::Off), 30.0);
+        assert_eq!(min_running(30.0, 30.0, BelowMin::Min), 30.0);
+        assert_eq!(min_running(80.0, 30.0, BelowMin::Min), 80.0);
+    }
+}
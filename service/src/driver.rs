@@ -0,0 +1,262 @@
+use anyhow::{
+    Result,
+    anyhow
+};
+
+use std::sync::{
+    Arc,
+    Mutex
+};
+
+use super::config::{
+    FanConfig,
+    FanDriverKind,
+    PigpioMode,
+    PwmMode
+};
+
+use super::sysfs_pwm::SysfsPwm;
+use super::pigpiod::Pigpiod;
+use super::tach::Tach;
+
+#[cfg(feature = "pigpio")]
+use super::pi::Fan;
+
+/// Fan PWM output.
+///
+/// The duty-cycle is between 0 (off) and the configured
+/// range (fully on).
+pub trait FanDriver: Send {
+    /// Update the duty-cycle.
+    fn set_duty(&mut self, duty: u32) -> Result<()>;
+    /// Last duty-cycle set.
+    fn get_duty(&self) -> u32;
+    /// Release the hardware, the driver is not used afterwards.
+    fn shutdown(&mut self) -> Result<()>;
+    /// Measured fan speed, `None` without a tachometer.
+    fn rpm(&mut self) -> Result<Option<f32>> {
+        Ok(None)
+    }
+}
+
+/// Driver with a tachometer attached.
+pub struct WithTach {
+    driver: Box<dyn FanDriver>,
+    tach: Tach
+}
+
+impl WithTach {
+    /// Attach a tachometer to a driver.
+    #[rustfmt::skip]
+    pub fn new(driver: Box<dyn FanDriver>, tach: Tach) -> Self {
+        Self {
+            driver,
+            tach
+        }
+    }
+}
+
+impl FanDriver for WithTach {
+    fn set_duty(&mut self, duty: u32) -> Result<()> {
+        self.driver.set_duty(duty)
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.driver.get_duty()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.tach.close();
+        self.driver.shutdown()
+    }
+
+    fn rpm(&mut self) -> Result<Option<f32>> {
+        Ok(Some(self.tach.rpm()?))
+    }
+}
+
+/// Stand-in for a driver that was shut down while no other
+/// could be opened, the last duty stays readable and every
+/// new one is refused.
+pub struct Closed {
+    duty: u32
+}
+
+impl Closed {
+    /// Stand in for a driver last at `duty`.
+    pub fn new(duty: u32) -> Self {
+        Self { duty }
+    }
+}
+
+impl FanDriver for Closed {
+    fn set_duty(&mut self, _: u32) -> Result<()> {
+        Err(anyhow!("fan driver is closed"))
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Open the driver selected by `fan.driver`, with the
+/// tachometer attached when `fan.tach` is set.
+///
+/// #Example
+///
+/// ```
+/// let mut fan = open(&FanConfig::default()).unwrap();
+/// fan.set_duty(255).unwrap();
+/// ```
+#[rustfmt::skip]
+pub fn open(config: &FanConfig) -> Result<Box<dyn FanDriver>> {
+    let driver: Box<dyn FanDriver> = match (config.driver, config.pigpio_mode) {
+        #[cfg(feature = "pigpio")]
+        (FanDriverKind::Pigpio, PigpioMode::InProcess) => Box::new(Fan::new(
+            config.pin,
+            config.frequency,
+            config.range,
+            config.pwm == PwmMode::Hardware
+        )?),
+        #[cfg(not(feature = "pigpio"))]
+        (FanDriverKind::Pigpio, PigpioMode::InProcess) => return Err(anyhow!(
+            "fan.pigpio_mode \"in-process\" is not available, build with `--features pigpio`"
+        )),
+        (FanDriverKind::Pigpio, PigpioMode::Daemon) => Box::new(Pigpiod::new(
+            &config.pigpiod_address,
+            config.pin,
+            config.frequency,
+            config.range,
+            config.pwm == PwmMode::Hardware
+        )?),
+        (FanDriverKind::Sysfs, _) => Box::new(SysfsPwm::new(
+            &config.sysfs_root,
+            config.chip,
+            config.channel,
+            config.frequency,
+            config.range
+        )?),
+        (FanDriverKind::Memory, _) => Box::new(Recording::default())
+    };
+
+    Ok(match &config.tach {
+        Some(tach) => Box::new(WithTach::new(driver, Tach::open(tach)?)),
+        None => driver
+    })
+}
+
+/// Everything a `Recording` driver was asked to do.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    /// Every duty-cycle set, in order.
+    pub history: Vec<u32>,
+    /// Whether `shutdown` was called.
+    pub shutdown: bool,
+}
+
+/// In-memory driver recording every call.
+///
+/// Clones share the same record, so a test can keep one
+/// clone and hand the other to the monitor.
+///
+/// #Example
+///
+/// ```
+/// let recording = Recording::default();
+/// let mut fan: Box<dyn FanDriver> = Box::new(recording.clone());
+/// fan.set_duty(128).unwrap();
+/// assert_eq!(recording.record().history, vec![128]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    record: Arc<Mutex<Record>>
+}
+
+impl Recording {
+    /// Snapshot of the record.
+    #[cfg(test)]
+    pub fn record(&self) -> Record {
+        self.record.lock().unwrap().clone()
+    }
+}
+
+impl FanDriver for Recording {
+    #[rustfmt::skip]
+    fn set_duty(&mut self, duty: u32) -> Result<()> {
+        let mut record = self.record.lock().unwrap();
+        if record.shutdown {
+            return Err(anyhow!("fan is shut down"))
+        }
+
+        record.history.push(duty);
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.record.lock().unwrap().history.last().copied().unwrap_or(0)
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        self.record.lock().unwrap().shutdown = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::testutil::tracked_tach;
+
+    #[test]
+    fn records_every_duty_in_order() {
+        let recording = Recording::default();
+        let mut fan: Box<dyn FanDriver> = Box::new(recording.clone());
+        assert_eq!(fan.get_duty(), 0);
+        fan.set_duty(128).unwrap();
+        fan.set_duty(255).unwrap();
+        fan.set_duty(0).unwrap();
+        assert_eq!(fan.get_duty(), 0);
+        assert_eq!(fan.rpm().unwrap(), None);
+        assert_eq!(recording.record(), Record { history: vec![128, 255, 0], shutdown: false });
+    }
+
+    #[test]
+    fn refuses_duties_after_shutdown() {
+        let recording = Recording::default();
+        let mut fan = recording.clone();
+        fan.set_duty(64).unwrap();
+        fan.shutdown().unwrap();
+        assert!(fan.set_duty(128).is_err());
+        assert_eq!(recording.record(), Record { history: vec![64], shutdown: true });
+    }
+
+    #[test]
+    fn opens_the_memory_driver() {
+        let config = FanConfig {
+            driver: FanDriverKind::Memory,
+            ..FanConfig::default()
+        };
+        let mut fan = open(&config).unwrap();
+        fan.set_duty(200).unwrap();
+        assert_eq!(fan.get_duty(), 200);
+        fan.shutdown().unwrap();
+    }
+
+    #[test]
+    fn releases_the_tach_on_shutdown() {
+        let recording = Recording::default();
+        let (tach, closed) = tracked_tach();
+        let mut fan = WithTach::new(Box::new(recording.clone()), tach);
+        fan.set_duty(128).unwrap();
+        assert!(!closed.load(Ordering::SeqCst));
+
+        fan.shutdown().unwrap();
+        assert!(closed.load(Ordering::SeqCst));
+        assert!(recording.record().shutdown);
+    }
+}
@@ -0,0 +1,411 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::collections::VecDeque;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH
+};
+
+use std::sync::{
+    Arc,
+    Mutex
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use serde::Serialize;
+
+use super::config::HistoryConfig;
+
+/// First bytes of a history file, with the format version.
+const MAGIC: &[u8; 8] = b"RADHIST1";
+
+/// Bytes of one sample in a history file.
+const RECORD: usize = 20;
+
+/// State of the fan at one poll.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Sample {
+    /// Secs since the epoch.
+    pub time: u64,
+    /// Temperature(°C), `None` when it could not be read.
+    pub temp: Option<f32>,
+    /// Duty(%) applied.
+    pub duty: f32,
+    /// Fan speed, `None` without a tachometer.
+    pub rpm: Option<f32>,
+}
+
+impl Sample {
+    /// Sample taken now.
+    pub fn now(temp: Option<f32>, duty: f32, rpm: Option<f32>) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        Self { time, temp, duty, rpm }
+    }
+
+    /// Little-endian time, temperature, duty and speed, with NaN
+    /// for a missing value.
+    #[rustfmt::skip]
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.time.to_le_bytes());
+        bytes.extend_from_slice(&self.temp.unwrap_or(f32::NAN).to_le_bytes());
+        bytes.extend_from_slice(&self.duty.to_le_bytes());
+        bytes.extend_from_slice(&self.rpm.unwrap_or(f32::NAN).to_le_bytes());
+    }
+
+    #[rustfmt::skip]
+    fn decode(record: &[u8]) -> Self {
+        let f32_at = |at: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&record[at..at + 4]);
+            Some(f32::from_le_bytes(bytes)).filter(|value| !value.is_nan())
+        };
+
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&record[..8]);
+        Self {
+            time: u64::from_le_bytes(time),
+            temp: f32_at(8),
+            duty: f32_at(12).unwrap_or(0.0),
+            rpm: f32_at(16)
+        }
+    }
+}
+
+/// Ring buffer of the last samples.
+#[derive(Debug, Default)]
+struct Ring {
+    capacity: usize,
+    samples: VecDeque<Sample>
+}
+
+/// Temperature, duty and speed history.
+///
+/// The monitor records a sample per poll and the control socket
+/// reads them, clones share the same samples. Once full, every
+/// new sample drops the oldest one.
+///
+/// #Example
+///
+/// ```
+/// let history = History::new(2);
+/// history.record(Sample { time: 1, temp: Some(45.0), duty: 25.0, rpm: None });
+/// history.record(Sample { time: 2, temp: Some(46.0), duty: 30.0, rpm: None });
+/// history.record(Sample { time: 3, temp: Some(47.0), duty: 35.0, rpm: None });
+/// assert_eq!(history.range(0, u64::MAX).len(), 2);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    ring: Arc<Mutex<Ring>>
+}
+
+impl History {
+    /// Empty history keeping at most `capacity` samples.
+    #[rustfmt::skip]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ring: Arc::new(Mutex::new(Ring {
+                capacity,
+                samples: VecDeque::with_capacity(capacity)
+            }))
+        }
+    }
+
+    /// History restored from a file written by `save`.
+    ///
+    /// A missing file gives an empty history, a file holding more
+    /// than `capacity` samples only keeps the newest.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let history = History::load(Path::new("/var/lib/radiator/history.bin"), 8640).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn load(path: &Path, capacity: usize) -> Result<Self> {
+        let history = Self::new(capacity);
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e).with_context(|| format!("cannot read history file {:?}", path))
+        };
+
+        if !bytes.starts_with(MAGIC) {
+            return Err(anyhow!("{:?} is not a history file", path))
+        }
+
+        for record in bytes[MAGIC.len()..].chunks_exact(RECORD) {
+            history.record(Sample::decode(record));
+        }
+
+        Ok(history)
+    }
+
+    /// Write every sample to a file, replacing it at once so a
+    /// crash never leaves half a file behind.
+    ///
+    /// The samples reach the disk before the rename, and the rename
+    /// itself before returning, so a power cut leaves either the old
+    /// file or the new one.
+    #[rustfmt::skip]
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut bytes = MAGIC.to_vec();
+        for sample in self.ring.lock().unwrap().samples.iter() {
+            sample.encode(&mut bytes);
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new(".")
+        };
+
+        fs::create_dir_all(dir).with_context(|| format!("cannot create {:?}", dir))?;
+        let partial = path.with_extension("partial");
+        File::create(&partial)
+            .and_then(|mut file| {
+                file.write_all(&bytes)?;
+                file.sync_all()
+            })
+            .with_context(|| format!("cannot write {:?}", partial))?;
+        fs::rename(&partial, path).with_context(|| format!("cannot replace {:?}", path))?;
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("cannot sync {:?}", dir))
+    }
+
+    /// Add a sample, dropping the oldest one when full.
+    #[rustfmt::skip]
+    pub fn record(&self, sample: Sample) {
+        let mut ring = self.ring.lock().unwrap();
+        if ring.capacity == 0 {
+            return
+        }
+
+        while ring.samples.len() >= ring.capacity {
+            ring.samples.pop_front();
+        }
+
+        ring.samples.push_back(sample);
+    }
+
+    /// Keep at most `capacity` samples from now on, dropping the
+    /// oldest ones beyond it.
+    #[rustfmt::skip]
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut ring = self.ring.lock().unwrap();
+        while ring.samples.len() > capacity {
+            ring.samples.pop_front();
+        }
+
+        ring.capacity = capacity;
+    }
+
+    /// Samples taken from `from` to `to`(secs since the epoch),
+    /// both included, oldest first.
+    #[rustfmt::skip]
+    pub fn range(&self, from: u64, to: u64) -> Vec<Sample> {
+        self.ring.lock().unwrap()
+            .samples
+            .iter()
+            .filter(|sample| (from..=to).contains(&sample.time))
+            .copied()
+            .collect()
+    }
+}
+
+/// Open the history as configured, restored from `history.file`
+/// when set. A file that cannot be read is logged and replaced by
+/// an empty history, losing the samples beats not cooling.
+///
+/// #Example
+///
+/// ```
+/// let history = open(&HistoryConfig::default());
+/// ```
+#[rustfmt::skip]
+pub fn open(config: &HistoryConfig) -> History {
+    match &config.file {
+        Some(file) => History::load(file, config.capacity).unwrap_or_else(|e| {
+            warn!("{:#}, starting with an empty history", e);
+            History::new(config.capacity)
+        }),
+        None => History::new(config.capacity)
+    }
+}
+
+/// Reduce samples to at most `points` by averaging runs of
+/// consecutive samples, every run keeps the time of its first one.
+///
+/// A missing temperature or speed is left out of its run's mean,
+/// a run without any stays missing.
+///
+/// #Example
+///
+/// ```
+/// let samples = (0..10)
+///     .map(|i| Sample { time: i, temp: Some(i as f32), duty: 50.0, rpm: None })
+///     .collect::<Vec<_>>();
+/// let reduced = downsample(&samples, 5);
+/// assert_eq!(reduced.len(), 5);
+/// assert_eq!(reduced[0].temp, Some(0.5));
+/// ```
+#[rustfmt::skip]
+pub fn downsample(samples: &[Sample], points: usize) -> Vec<Sample> {
+    if points == 0 || samples.len() <= points {
+        return samples.to_vec()
+    }
+
+    let mean = |values: &mut dyn Iterator<Item = f32>| {
+        let (sum, count) = values.fold((0.0, 0), |(sum, count), value| (sum + value, count + 1));
+        if count == 0 { None } else { Some(sum / count as f32) }
+    };
+
+    let run = samples.len().div_ceil(points);
+    samples.chunks(run)
+        .map(|chunk| Sample {
+            time: chunk[0].time,
+            temp: mean(&mut chunk.iter().filter_map(|s| s.temp)),
+            duty: mean(&mut chunk.iter().map(|s| s.duty)).unwrap_or(0.0),
+            rpm: mean(&mut chunk.iter().filter_map(|s| s.rpm))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch;
+
+    fn sample(time: u64, temp: Option<f32>, duty: f32, rpm: Option<f32>) -> Sample {
+        Sample { time, temp, duty, rpm }
+    }
+
+    #[test]
+    fn encodes_and_decodes_a_sample() {
+        for sample in &[
+            sample(1_700_000_000, Some(47.2), 35.5, Some(1810.0)),
+            sample(u64::MAX, Some(-5.5), 100.0, None),
+            sample(0, None, 0.0, Some(0.0))
+        ] {
+            let mut bytes = Vec::new();
+            sample.encode(&mut bytes);
+            assert_eq!(bytes.len(), RECORD);
+            assert_eq!(Sample::decode(&bytes), *sample);
+        }
+    }
+
+    #[test]
+    fn encodes_little_endian_with_nan_for_missing_values() {
+        let mut bytes = Vec::new();
+        sample(1, None, 50.0, None).encode(&mut bytes);
+        assert_eq!(&bytes[..8], &[1, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(f32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]).is_nan());
+        assert_eq!(&bytes[12..16], &50.0f32.to_le_bytes());
+    }
+
+    #[test]
+    fn saves_and_loads_the_samples() {
+        let path = scratch("history-roundtrip").join("state/history.bin");
+        let history = History::new(10);
+        let samples = vec![
+            sample(1, Some(45.0), 25.0, None),
+            sample(2, None, 100.0, Some(900.0)),
+            sample(3, Some(46.5), 30.0, Some(1200.0))
+        ];
+        for sample in &samples {
+            history.record(*sample);
+        }
+
+        history.save(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), (MAGIC.len() + 3 * RECORD) as u64);
+        assert!(!path.with_extension("partial").exists());
+        assert_eq!(History::load(&path, 10).unwrap().range(0, u64::MAX), samples);
+        // a smaller capacity keeps the newest.
+        assert_eq!(History::load(&path, 2).unwrap().range(0, u64::MAX), samples[1..]);
+    }
+
+    #[test]
+    fn replaces_a_saved_file() {
+        let path = scratch("history-replace").join("history.bin");
+        let history = History::new(10);
+        history.record(sample(1, Some(45.0), 25.0, None));
+        history.save(&path).unwrap();
+        history.record(sample(2, Some(46.0), 30.0, None));
+        history.save(&path).unwrap();
+        assert_eq!(History::load(&path, 10).unwrap().range(0, u64::MAX).len(), 2);
+    }
+
+    #[test]
+    fn loads_a_missing_file_empty_and_refuses_other_files() {
+        let dir = scratch("history-load");
+        assert!(History::load(&dir.join("none.bin"), 10).unwrap().range(0, u64::MAX).is_empty());
+
+        fs::write(dir.join("other.bin"), "time,temp_c,duty,rpm\n").unwrap();
+        let e = History::load(&dir.join("other.bin"), 10).unwrap_err();
+        assert!(e.to_string().ends_with("is not a history file"));
+    }
+
+    #[test]
+    fn drops_the_oldest_samples() {
+        let history = History::new(3);
+        for time in 1..=5 {
+            history.record(sample(time, Some(40.0), 0.0, None));
+        }
+
+        let times = |history: &History| history.range(0, u64::MAX).iter().map(|s| s.time).collect::<Vec<_>>();
+        assert_eq!(times(&history), vec![3, 4, 5]);
+        assert_eq!(history.range(4, 4).len(), 1);
+
+        history.set_capacity(2);
+        assert_eq!(times(&history), vec![4, 5]);
+        history.set_capacity(0);
+        history.record(sample(6, None, 0.0, None));
+        assert!(times(&history).is_empty());
+    }
+
+    #[test]
+    fn downsamples_runs_of_samples() {
+        let samples = (0..10)
+            .map(|i| sample(i, Some(i as f32), i as f32 * 10.0, None))
+            .collect::<Vec<_>>();
+        let reduced = downsample(&samples, 4);
+        // runs of 3, the last one shorter.
+        assert_eq!(reduced, vec![
+            sample(0, Some(1.0), 10.0, None),
+            sample(3, Some(4.0), 40.0, None),
+            sample(6, Some(7.0), 70.0, None),
+            sample(9, Some(9.0), 90.0, None)
+        ]);
+    }
+
+    #[test]
+    fn downsamples_missing_values_out_of_the_mean() {
+        let samples = vec![
+            sample(0, None, 50.0, Some(1000.0)),
+            sample(1, Some(40.0), 50.0, None),
+            sample(2, None, 50.0, None),
+            sample(3, None, 50.0, None)
+        ];
+        assert_eq!(downsample(&samples, 2), vec![
+            sample(0, Some(40.0), 50.0, Some(1000.0)),
+            sample(2, None, 50.0, None)
+        ]);
+    }
+
+    #[test]
+    fn keeps_few_samples_as_they_are() {
+        let samples = vec![sample(0, Some(40.0), 0.0, None), sample(1, Some(41.0), 0.0, None)];
+        assert_eq!(downsample(&samples, 2), samples);
+        assert_eq!(downsample(&samples, 0), samples);
+        assert!(downsample(&[], 10).is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::{
+    Duration,
+    Instant
+};
+
+/// Fan on/off switch with a hysteresis band and a minimum dwell.
+///
+/// The fan turns on above `on` and turns off only below `off`,
+/// so a temperature sitting between the two keeps the current state.
+/// After every change the state is held for at least `min_dwell`,
+/// whatever the temperature does.
+#[derive(Debug, Clone)]
+pub struct Hysteresis {
+    on: f32,
+    off: f32,
+    min_dwell: Duration,
+    running: bool,
+    since: Option<Instant>
+}
+
+impl Hysteresis {
+    /// Create a switch that starts in the off state.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// Hysteresis::new(42.0, 38.0, Duration::from_secs(30));
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(on: f32, off: f32, min_dwell: Duration) -> Self {
+        Self {
+            on,
+            off,
+            min_dwell,
+            running: false,
+            since: None
+        }
+    }
+
+    /// Feed a temperature reading taken at `now`,
+    /// returns whether the fan should run.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut switch = Hysteresis::new(42.0, 38.0, Duration::from_secs(0));
+    /// let now = Instant::now();
+    /// assert_eq!(switch.update(43.0, now), true);
+    /// assert_eq!(switch.update(40.0, now), true);
+    /// assert_eq!(switch.update(37.0, now), false);
+    /// ```
+    #[rustfmt::skip]
+    pub fn update(&mut self, temp: f32, now: Instant) -> bool {
+        let wanted = if self.running {
+            temp >= self.off
+        } else {
+            temp > self.on
+        };
+
+        let settled = self.since
+            .map(|since| now.saturating_duration_since(since) >= self.min_dwell)
+            .unwrap_or(true);
+        if wanted != self.running && settled {
+            self.running = wanted;
+            self.since = Some(now);
+        }
+
+        self.running
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run readings taken one second apart, returns the states.
+    fn feed(switch: &mut Hysteresis, start: Instant, temps: &[f32]) -> Vec<bool> {
+        temps.iter()
+            .enumerate()
+            .map(|(i, temp)| switch.update(*temp, start + Duration::from_secs(i as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn stays_in_state_within_the_band() {
+        let mut switch = Hysteresis::new(42.0, 38.0, Duration::from_secs(0));
+        let states = feed(&mut switch, Instant::now(), &[
+            40.0, 42.0, 42.1, 41.0, 39.0, 38.0, 37.9, 40.0, 41.9, 42.5
+        ]);
+        assert_eq!(states, vec![
+            false, false, true, true, true, true, false, false, false, true
+        ]);
+    }
+
+    #[test]
+    fn does_not_hunt_around_one_threshold() {
+        let mut switch = Hysteresis::new(42.0, 38.0, Duration::from_secs(0));
+        let temps = [39.8, 40.2, 39.9, 40.1, 40.0, 39.7, 40.3];
+        assert!(feed(&mut switch, Instant::now(), &temps).iter().all(|running| !running));
+    }
+
+    #[test]
+    fn holds_each_state_for_min_dwell() {
+        let start = Instant::now();
+        let mut switch = Hysteresis::new(42.0, 38.0, Duration::from_secs(10));
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // the first change is free.
+        assert!(switch.update(45.0, at(0)));
+        // off is wanted but the dwell is not over.
+        assert!(switch.update(30.0, at(5)));
+        assert!(switch.update(30.0, at(9)));
+        // exactly `min_dwell` after the change.
+        assert!(!switch.update(30.0, at(10)));
+        // and again for the way back on.
+        assert!(!switch.update(45.0, at(19)));
+        assert!(switch.update(45.0, at(20)));
+    }
+
+    #[test]
+    fn dwell_does_not_delay_a_state_no_longer_wanted() {
+        let start = Instant::now();
+        let mut switch = Hysteresis::new(42.0, 38.0, Duration::from_secs(10));
+        assert!(switch.update(45.0, start));
+        assert!(switch.update(30.0, start + Duration::from_secs(5)));
+        // back in the band before the dwell ended, so it stays on.
+        assert!(switch.update(40.0, start + Duration::from_secs(11)));
+    }
+}
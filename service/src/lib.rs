@@ -0,0 +1,76 @@
+#[macro_use]
+mod log;
+#[cfg(feature = "pigpio")]
+mod pi;
+mod driver;
+mod sysfs_pwm;
+mod pigpiod;
+mod tach;
+mod stall;
+mod temp;
+mod curve;
+mod hysteresis;
+mod slew;
+mod signal;
+mod notify;
+mod metrics;
+mod history;
+mod control;
+mod pid;
+mod config;
+mod monitor;
+pub mod ctl;
+#[cfg(test)]
+mod testutil;
+
+use anyhow::Result;
+use config::Config;
+use monitor::Monitor;
+use notify::Notifier;
+
+/// Run the service until it is stopped.
+///
+/// Reads the configuration, serves the metrics and the control
+/// socket when configured, and runs the monitor.
+#[rustfmt::skip]
+pub fn run() -> Result<()> {
+    let signals = signal::listen()?;
+    let config = match Config::load()? {
+        Some(config) => config,
+        None => return Ok(())
+    };
+
+    log::configure(&config.log);
+    info!(
+        [
+            MODE = log::name(&config.mode),
+            POLL_INTERVAL = config.poll_interval,
+            SENSOR = log::name(&config.sensor.source),
+            DRIVER = log::name(&config.fan.driver)
+        ],
+        "radiator {} starting: {} mode, polling every {}s, {} sensor, {} fan driver",
+        env!("CARGO_PKG_VERSION"),
+        log::name(&config.mode),
+        config.poll_interval,
+        log::name(&config.sensor.source),
+        log::name(&config.fan.driver)
+    );
+    debug!("configuration: {}", serde_json::to_string(&config)?);
+
+    let exporter = config.metrics.clone();
+    let socket = config.control.clone();
+    let notifier = Notifier::from_env()?;
+    notifier.check(&config)?;
+    let monitor = Monitor::builder(config)?
+        .with_signals(signals.clone())
+        .with_notifier(notifier);
+    if let Some(exporter) = exporter {
+        metrics::serve(&exporter.address, monitor.metrics())?;
+    }
+
+    if let Some(socket) = socket {
+        control::serve(&socket, monitor.control(), signals)?;
+    }
+
+    monitor.run()
+}
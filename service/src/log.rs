@@ -0,0 +1,369 @@
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::time::{
+    Duration,
+    Instant,
+    SystemTime,
+    UNIX_EPOCH
+};
+
+use serde::Serialize;
+use serde_json::{
+    Map,
+    Value
+};
+
+use super::config::{
+    LogConfig,
+    LogFormat,
+    LogLevel
+};
+
+/// journald native protocol socket.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Write a record at a level, with optional structured fields.
+///
+/// Field names are journald field names, upper case letters,
+/// digits and underscores.
+///
+/// #Example
+///
+/// ```
+/// log!(LogLevel::Info, [TEMP_C = 45.0, DUTY = 25.0], "duty {}%", 25);
+/// log!(LogLevel::Warn, "fan stalled");
+/// ```
+macro_rules! log {
+    ($level:expr, [$($key:ident = $value:expr),* $(,)?], $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write(
+                $level,
+                (file!(), line!()),
+                &format!($($arg)+),
+                &[$((stringify!($key), serde_json::json!($value))),*]
+            )
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        log!($level, [], $($arg)+)
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)+) => { log!($crate::config::LogLevel::Error, $($arg)+) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => { log!($crate::config::LogLevel::Warn, $($arg)+) };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { log!($crate::config::LogLevel::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { log!($crate::config::LogLevel::Debug, $($arg)+) };
+}
+
+/// Records written and suppressed at one call site in the
+/// current rate limit window.
+#[derive(Debug)]
+struct Site {
+    file: &'static str,
+    line: u32,
+    start: Instant,
+    written: u32,
+    suppressed: u32
+}
+
+/// Process-wide log settings.
+#[derive(Debug)]
+struct Logger {
+    level: LogLevel,
+    format: LogFormat,
+    rate_limit: u32,
+    rate_window: Duration,
+    journal: Option<UnixDatagram>,
+    sites: Vec<Site>
+}
+
+impl Logger {
+    /// Count a warning or an error against the rate limit of its
+    /// call site at `now`.
+    ///
+    /// Returns how many records the site dropped since it last
+    /// wrote one, or `None` when this one is dropped too.
+    #[rustfmt::skip]
+    fn admit(&mut self, (file, line): (&'static str, u32), now: Instant) -> Option<u32> {
+        let index = match self.sites.iter().position(|s| s.file == file && s.line == line) {
+            Some(index) => index,
+            None => {
+                self.sites.push(Site { file, line, start: now, written: 0, suppressed: 0 });
+                self.sites.len() - 1
+            }
+        };
+
+        let site = &mut self.sites[index];
+        if now.saturating_duration_since(site.start) >= self.rate_window {
+            site.start = now;
+            site.written = 0;
+        }
+
+        if site.written >= self.rate_limit {
+            site.suppressed += 1;
+            return None
+        }
+
+        site.written += 1;
+        Some(std::mem::take(&mut site.suppressed))
+    }
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: LogLevel::Info,
+    format: LogFormat::Plain,
+    rate_limit: 3,
+    rate_window: Duration::from_secs(300),
+    journal: None,
+    sites: Vec::new()
+});
+
+/// Apply the log settings, records are written as plain text at
+/// the info level until then.
+///
+/// #Example
+///
+/// ```
+/// configure(&LogConfig::default());
+/// info!("started");
+/// ```
+#[rustfmt::skip]
+pub fn configure(config: &LogConfig) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    logger.level = config.level;
+    logger.format = config.format;
+    logger.rate_limit = config.rate_limit;
+    logger.rate_window = Duration::from_secs(config.rate_window);
+    logger.journal = match config.format {
+        LogFormat::Journald => UnixDatagram::unbound().ok(),
+        _ => None
+    };
+}
+
+/// Whether records at `level` are written.
+pub fn enabled(level: LogLevel) -> bool {
+    level <= LOGGER.lock().unwrap_or_else(|e| e.into_inner()).level
+}
+
+/// Name of a config value as written in the config file,
+/// e.g. `curve` for `Mode::Curve`.
+pub fn name<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(name)) => name,
+        Ok(value) => value.to_string(),
+        Err(_) => String::new()
+    }
+}
+
+/// A reading as a field value, rounded to a tenth so that the
+/// f32 doesn't show up as e.g. `52.29999923706055`.
+pub fn tenths(value: f32) -> f64 {
+    (f64::from(value) * 10.0).round() / 10.0
+}
+
+/// Write a record, use the level macros instead.
+///
+/// Warnings and errors beyond `rate_limit` per call site within
+/// `rate_window` are dropped, the next record written there tells
+/// how many were.
+#[rustfmt::skip]
+pub fn write(level: LogLevel, (file, line): (&'static str, u32), message: &str, fields: &[(&str, Value)]) {
+    let mut logger = LOGGER.lock().unwrap_or_else(|e| e.into_inner());
+    let mut suppressed = 0;
+    if level <= LogLevel::Warn && logger.rate_limit > 0 {
+        match logger.admit((file, line), Instant::now()) {
+            Some(dropped) => suppressed = dropped,
+            None => return
+        }
+    }
+
+    let message = match suppressed {
+        0 => message.to_string(),
+        n => format!("{} ({} similar messages suppressed)", message, n)
+    };
+
+    let mut fields = fields.to_vec();
+    if suppressed > 0 {
+        fields.push(("SUPPRESSED", Value::from(suppressed)));
+    }
+
+    let text = match (logger.format, &logger.journal) {
+        (LogFormat::Journald, Some(journal)) => {
+            let record = journal_record(level, (file, line), &message, &fields);
+            if journal.send_to(&record, JOURNAL_SOCKET).is_ok() {
+                return
+            }
+
+            plain(level, &message)
+        },
+        (LogFormat::Json, _) => json(level, &message, &fields, SystemTime::now()),
+        _ => plain(level, &message)
+    };
+
+    let _ = std::io::stderr().write_all(text.as_bytes());
+}
+
+/// Lower case name of a level.
+#[rustfmt::skip]
+fn label(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Error => "error",
+        LogLevel::Warn => "warn",
+        LogLevel::Info => "info",
+        LogLevel::Debug => "debug"
+    }
+}
+
+/// A line of text for stderr.
+fn plain(level: LogLevel, message: &str) -> String {
+    format!("{}: {}\n", label(level), message)
+}
+
+/// A JSON object line for stderr, with the time in secs since
+/// the epoch.
+#[rustfmt::skip]
+fn json(level: LogLevel, message: &str, fields: &[(&str, Value)], time: SystemTime) -> String {
+    let time = time
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs_f64())
+        .unwrap_or_default();
+    let mut record = Map::new();
+    record.insert("time".to_string(), Value::from((time * 1000.0).round() / 1000.0));
+    record.insert("level".to_string(), Value::from(label(level)));
+    record.insert("message".to_string(), Value::from(message));
+    for (key, value) in fields {
+        record.insert(key.to_string(), value.clone());
+    }
+
+    format!("{}\n", Value::Object(record))
+}
+
+/// A journald native protocol datagram.
+///
+/// Every field is a `KEY=value` line, a value holding a newline
+/// is written as the key, a line break, its little-endian 64-bit
+/// length and the raw value instead.
+#[rustfmt::skip]
+fn journal_record(level: LogLevel, (file, line): (&str, u32), message: &str, fields: &[(&str, Value)]) -> Vec<u8> {
+    let priority = match level {
+        LogLevel::Error => "3",
+        LogLevel::Warn => "4",
+        LogLevel::Info => "6",
+        LogLevel::Debug => "7"
+    };
+
+    let mut record = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        record.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            record.push(b'\n');
+            record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            record.push(b'=');
+        }
+
+        record.extend_from_slice(value.as_bytes());
+        record.push(b'\n');
+    };
+
+    field("MESSAGE", message);
+    field("PRIORITY", priority);
+    field("SYSLOG_IDENTIFIER", "radiator");
+    field("CODE_FILE", file);
+    field("CODE_LINE", &line.to_string());
+    for (key, value) in fields {
+        match value {
+            Value::String(text) => field(key, text),
+            value => field(key, &value.to_string())
+        }
+    }
+
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Logger allowing `limit` records per site and minute.
+    fn logger(limit: u32) -> Logger {
+        Logger {
+            level: LogLevel::Info,
+            format: LogFormat::Plain,
+            rate_limit: limit,
+            rate_window: Duration::from_secs(60),
+            journal: None,
+            sites: Vec::new()
+        }
+    }
+
+    #[test]
+    fn drops_records_over_the_limit_and_counts_them() {
+        let mut logger = logger(2);
+        let start = Instant::now();
+        let site = ("monitor.rs", 10);
+        assert_eq!(logger.admit(site, start), Some(0));
+        assert_eq!(logger.admit(site, start + Duration::from_secs(1)), Some(0));
+        assert_eq!(logger.admit(site, start + Duration::from_secs(2)), None);
+        assert_eq!(logger.admit(site, start + Duration::from_secs(3)), None);
+
+        // another line has a limit of its own.
+        assert_eq!(logger.admit(("monitor.rs", 11), start + Duration::from_secs(3)), Some(0));
+
+        // the next window writes again, telling how many were dropped.
+        assert_eq!(logger.admit(site, start + Duration::from_secs(59)), None);
+        assert_eq!(logger.admit(site, start + Duration::from_secs(60)), Some(3));
+        assert_eq!(logger.admit(site, start + Duration::from_secs(61)), Some(0));
+        assert_eq!(logger.admit(site, start + Duration::from_secs(62)), None);
+    }
+
+    #[test]
+    fn encodes_single_line_journal_fields() {
+        let fields = [("TEMP_C", Value::from(52.5)), ("ERROR", Value::from("parse"))];
+        let record = journal_record(LogLevel::Warn, ("src/monitor.rs", 42), "cannot read", &fields);
+        assert_eq!(String::from_utf8(record).unwrap(), "\
+            MESSAGE=cannot read\n\
+            PRIORITY=4\n\
+            SYSLOG_IDENTIFIER=radiator\n\
+            CODE_FILE=src/monitor.rs\n\
+            CODE_LINE=42\n\
+            TEMP_C=52.5\n\
+            ERROR=parse\n");
+    }
+
+    #[test]
+    fn encodes_multi_line_journal_fields_with_their_length() {
+        let record = journal_record(LogLevel::Error, ("src/temp.rs", 7), "exit 1:\nno such file", &[]);
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&20u64.to_le_bytes());
+        expected.extend_from_slice(b"exit 1:\nno such file\nPRIORITY=3\n");
+        assert!(record.starts_with(&expected));
+    }
+
+    #[test]
+    fn encodes_plain_and_json_lines() {
+        assert_eq!(plain(LogLevel::Info, "duty 50%"), "info: duty 50%\n");
+
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let line = json(LogLevel::Debug, "temperature", &[("TEMP_C", Value::from(45.0))], time);
+        assert!(line.ends_with('\n'));
+        let record: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record, serde_json::json!({
+            "time": 1_700_000_000.123,
+            "level": "debug",
+            "message": "temperature",
+            "TEMP_C": 45.0
+        }));
+    }
+}
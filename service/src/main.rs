@@ -1,13 +1,3 @@
-mod pi;
-mod temp;
-mod monitor;
-
-use anyhow::Result;
-use monitor::Monitor;
-
-fn main() -> Result<()> {
-    Monitor::builder(
-        env!("RADIATOR_PIN").parse::<u8>()?, 
-        env!("RADIATOR_DELAY").parse::<u64>()?
-    )?.run()
+fn main() -> anyhow::Result<()> {
+    service::run()
 }
@@ -0,0 +1,346 @@
+use std::fmt::Write as _;
+use std::thread::spawn;
+use std::time::Duration;
+use std::io::{
+    Read,
+    Write
+};
+
+use std::net::{
+    SocketAddr,
+    TcpListener,
+    TcpStream
+};
+
+use std::sync::{
+    Arc,
+    Mutex
+};
+
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering
+};
+
+use anyhow::{
+    Result,
+    Context
+};
+
+use super::monitor::Status;
+use super::temp::SensorError;
+
+/// Upper bounds(secs) of the poll duration histogram buckets.
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// `SensorError::kind` of every sensor error counter.
+const SENSOR_ERRORS: [&str; 4] = ["io", "command", "timeout", "parse"];
+
+/// How long a client may take to send its request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connections served at once, the ones over it are closed
+/// unanswered.
+const CONNECTIONS: usize = 4;
+
+/// Values behind the exported metrics.
+#[derive(Debug, Default)]
+struct Values {
+    status: Option<Status>,
+    target: Option<f32>,
+    buckets: [u64; BUCKETS.len()],
+    latency_sum: f64,
+    polls: u64,
+    sensor_errors: [u64; SENSOR_ERRORS.len()],
+    failsafe: u64
+}
+
+/// Monitor metrics.
+///
+/// The monitor records into it and the exporter renders it, clones
+/// share the same values.
+///
+/// #Example
+///
+/// ```
+/// let metrics = Metrics::default();
+/// metrics.failsafe();
+/// assert!(metrics.render().contains("radiator_failsafe_activations_total 1"));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    values: Arc<Mutex<Values>>
+}
+
+impl Metrics {
+    /// Record a poll: the state it left, the duty(%) the controller
+    /// asked for when the temperature was read, and how long it took.
+    #[rustfmt::skip]
+    pub fn poll(&self, status: Status, target: Option<f32>, latency: Duration) {
+        let mut values = self.values.lock().unwrap();
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in values.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        values.latency_sum += secs;
+        values.polls += 1;
+        values.status = Some(status);
+        if target.is_some() {
+            values.target = target;
+        }
+    }
+
+    /// Count a failed temperature read.
+    #[rustfmt::skip]
+    pub fn sensor_error(&self, error: &SensorError) {
+        let mut values = self.values.lock().unwrap();
+        if let Some(index) = SENSOR_ERRORS.iter().position(|kind| *kind == error.kind()) {
+            values.sensor_errors[index] += 1;
+        }
+    }
+
+    /// Count a failsafe policy activation.
+    pub fn failsafe(&self) {
+        self.values.lock().unwrap().failsafe += 1;
+    }
+
+    /// Metrics in the Prometheus text exposition format.
+    #[rustfmt::skip]
+    pub fn render(&self) -> String {
+        let values = self.values.lock().unwrap();
+        let mut text = String::new();
+        let status = values.status.as_ref();
+        let mut gauge = |name: &str, help: &str, value: Option<f64>| {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+            if let Some(value) = value {
+                let _ = writeln!(text, "{} {}", name, value);
+            }
+        };
+
+        gauge(
+            "radiator_temperature_celsius",
+            "SoC temperature read at the last poll.",
+            status.and_then(|s| s.temp).map(f64::from)
+        );
+        gauge(
+            "radiator_duty_target_percent",
+            "Duty the controller asked for at the last temperature read.",
+            values.target.map(f64::from)
+        );
+        gauge(
+            "radiator_duty_applied_percent",
+            "Duty applied to the fan at the last poll.",
+            status.map(|s| f64::from(s.duty))
+        );
+        gauge(
+            "radiator_fan_rpm",
+            "Fan speed measured at the last poll.",
+            status.and_then(|s| s.rpm).map(f64::from)
+        );
+        gauge(
+            "radiator_fan_failed",
+            "Whether the fan failed to spin after every kick.",
+            status.map(|s| if s.fan_failed { 1.0 } else { 0.0 })
+        );
+
+        let name = "radiator_poll_duration_seconds";
+        let _ = writeln!(text, "# HELP {} Time a poll took, without the wait for the next one.", name);
+        let _ = writeln!(text, "# TYPE {} histogram", name);
+        for (count, bound) in values.buckets.iter().zip(BUCKETS.iter()) {
+            let _ = writeln!(text, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+
+        let _ = writeln!(text, "{}_bucket{{le=\"+Inf\"}} {}", name, values.polls);
+        let _ = writeln!(text, "{}_sum {}", name, values.latency_sum);
+        let _ = writeln!(text, "{}_count {}", name, values.polls);
+
+        let name = "radiator_sensor_errors_total";
+        let _ = writeln!(text, "# HELP {} Failed temperature reads by kind.", name);
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        for (kind, count) in SENSOR_ERRORS.iter().zip(values.sensor_errors.iter()) {
+            let _ = writeln!(text, "{}{{kind=\"{}\"}} {}", name, kind, count);
+        }
+
+        let name = "radiator_failsafe_activations_total";
+        let _ = writeln!(text, "# HELP {} Times the failsafe policy took over.", name);
+        let _ = writeln!(text, "# TYPE {} counter", name);
+        let _ = writeln!(text, "{} {}", name, values.failsafe);
+
+        let name = "radiator_build_info";
+        let _ = writeln!(text, "# HELP {} Build of the running service.", name);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+        let _ = writeln!(text, "{}{{version=\"{}\"}} 1", name, env!("CARGO_PKG_VERSION"));
+        text
+    }
+}
+
+/// Serve `GET /metrics` on `address` in the background, returns
+/// the address bound, e.g. the port picked for `127.0.0.1:0`.
+///
+/// A bare HTTP/1.1 responder, every connection gets one answer
+/// and is closed. Each connection is served on its own thread,
+/// so a client that connects and stays silent only holds up
+/// itself until it times out, and at most `CONNECTIONS` of them
+/// run at once: a flood of clients gets closed connections, not
+/// a thread each.
+///
+/// #Example
+///
+/// ```
+/// let metrics = Metrics::default();
+/// serve("127.0.0.1:9183", metrics.clone()).unwrap();
+/// // curl http://127.0.0.1:9183/metrics
+/// ```
+#[rustfmt::skip]
+pub fn serve(address: &str, metrics: Metrics) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address)
+        .with_context(|| format!("cannot listen on {:?}", address))?;
+    let local = listener.local_addr()?;
+    let active = Arc::new(AtomicUsize::new(0));
+    spawn(move || {
+        for stream in listener.incoming().flatten() {
+            if active.load(Ordering::SeqCst) >= CONNECTIONS {
+                debug!("metrics busy with {} connections, closing a new one", CONNECTIONS);
+                continue
+            }
+
+            let slot = Slot::take(&active);
+            let metrics = metrics.clone();
+            spawn(move || {
+                if let Err(e) = respond(stream, &metrics) {
+                    warn!("metrics request failed: {}", e);
+                }
+
+                drop(slot);
+            });
+        }
+    });
+
+    Ok(local)
+}
+
+/// One of the `CONNECTIONS` served at once, given back when dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(active: &Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::SeqCst);
+        Self(active.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Answer one request.
+#[rustfmt::skip]
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let size = stream.read(&mut buf)?;
+        if size == 0 {
+            break
+        }
+
+        request.extend_from_slice(&buf[..size]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut line = request.lines().next().unwrap_or("").split_whitespace();
+    let (status, body) = match (line.next(), line.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string())
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::status;
+    use std::time::Instant;
+
+    /// Send a raw request, returns the whole answer.
+    fn send(address: SocketAddr, request: &str) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+        stream.write_all(request.as_bytes())?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn get(address: SocketAddr, request: &str) -> String {
+        send(address, request).unwrap()
+    }
+
+    #[test]
+    fn serves_the_metrics_over_http() {
+        let metrics = Metrics::default();
+        metrics.poll(status(), Some(62.5), Duration::from_millis(20));
+        let address = serve("127.0.0.1:0", metrics).unwrap();
+
+        let response = get(address, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.contains("\nradiator_temperature_celsius 52.5\n"));
+        assert!(body.contains("\nradiator_duty_target_percent 62.5\n"));
+        assert!(body.contains("\nradiator_fan_rpm 1800\n"));
+        assert!(body.contains("\nradiator_poll_duration_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(body.contains("\nradiator_poll_duration_seconds_bucket{le=\"0.01\"} 0\n"));
+    }
+
+    #[test]
+    fn answers_other_requests_with_errors() {
+        let address = serve("127.0.0.1:0", Metrics::default()).unwrap();
+        assert!(get(address, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get(address, "POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    fn an_idle_client_does_not_block_scrapes() {
+        let address = serve("127.0.0.1:0", Metrics::default()).unwrap();
+        let _idle = TcpStream::connect(address).unwrap();
+        let started = Instant::now();
+        assert!(get(address, "GET /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn closes_connections_over_the_limit() {
+        let address = serve("127.0.0.1:0", Metrics::default()).unwrap();
+        let idle: Vec<TcpStream> = (0..CONNECTIONS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+
+        // accepted after the idle ones, closed without an answer.
+        let scrape = "GET /metrics HTTP/1.1\r\n\r\n";
+        assert_eq!(send(address, scrape).unwrap_or_default(), "");
+
+        // their slots come back once they hang up.
+        drop(idle);
+        let started = Instant::now();
+        while !send(address, scrape).unwrap_or_default().starts_with("HTTP/1.1 200 OK\r\n") {
+            assert!(started.elapsed() < TIMEOUT);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
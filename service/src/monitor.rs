@@ -1,76 +1,1229 @@
 use anyhow::Result;
-use std::time::Duration;
+use serde::Serialize;
+use std::fmt;
+use std::time::{
+    Duration,
+    Instant
+};
 use super::{
-    pi::Fan,
-    temp::*
+    driver::{
+        self,
+        Closed,
+        FanDriver
+    },
+    config::{
+        Config,
+        FailsafePolicy,
+        Mode
+    },
+    temp::{
+        self,
+        Celsius,
+        TemperatureSource
+    },
+    pid::Pid,
+    hysteresis::Hysteresis,
+    slew::SlewLimiter,
+    signal::Signals,
+    notify::Notifier,
+    log,
+    metrics::Metrics,
+    control::Control,
+    history::{
+        self,
+        History,
+        Sample
+    },
+    stall::{
+        self,
+        StallDetector,
+        Verdict
+    },
+    curve::{
+        FanCurve,
+        min_running,
+        to_pwm
+    }
 };
 
-use std::thread::{
-    sleep, 
-    spawn
-};
+use std::thread::spawn;
+
+/// Duty-cycle controller selected by `mode`.
+enum Controller {
+    Curve(FanCurve),
+    Pid(Pid)
+}
+
+impl Controller {
+    #[rustfmt::skip]
+    fn new(config: &Config) -> Result<Self> {
+        Ok(match config.mode {
+            Mode::Curve => Self::Curve(FanCurve::new(config.curve.points())?),
+            Mode::Pid => Self::Pid(Pid::new(
+                config.pid.setpoint,
+                (config.pid.kp, config.pid.ki, config.pid.kd),
+                config.pid.derivative_filter,
+                config.pid.min_duty,
+                config.pid.max_duty
+            ))
+        })
+    }
+}
+
+fn hysteresis(config: &Config) -> Option<Hysteresis> {
+    config.hysteresis.as_ref().map(|h| Hysteresis::new(
+        h.on,
+        h.off,
+        Duration::from_secs(h.min_dwell)
+    ))
+}
+
+#[rustfmt::skip]
+fn slew(config: &Config) -> Option<SlewLimiter> {
+    match (config.fan.ramp_up, config.fan.ramp_down) {
+        (None, None) => None,
+        (up, down) => Some(SlewLimiter::new(up, down))
+    }
+}
+
+fn stall(config: &Config) -> Option<StallDetector> {
+    config.fan.stall.as_ref().map(|s| StallDetector::new(
+        s.spin_threshold,
+        s.max_rpm,
+        s.min_ratio,
+        s.kicks
+    ))
+}
+
+/// Snapshot of the monitor state.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Status {
+    /// Temperature(°C) read at the last poll.
+    pub temp: Option<f32>,
+    /// Duty(%) applied.
+    pub duty: f32,
+    /// Fan speed measured at the last poll, `None` without
+    /// a tachometer.
+    pub rpm: Option<f32>,
+    /// Whether the fan failed to spin after every kick.
+    pub fan_failed: bool,
+    /// Consecutive failed temperature reads.
+    pub sensor_failures: u32,
+    /// Duty(%) set by hand, `None` in automatic mode.
+    pub manual: Option<f32>,
+    /// Seconds before the manual duty expires, `None` if it doesn't.
+    pub manual_expires: Option<u64>,
+}
+
+impl fmt::Display for Status {
+    #[rustfmt::skip]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.temp {
+            Some(temp) => write!(f, "{:.1}°C", temp)?,
+            None => write!(f, "temperature unavailable ({} failed reads)", self.sensor_failures)?
+        }
+
+        write!(f, ", duty {:.0}%", self.duty)?;
+        if let Some(rpm) = self.rpm {
+            write!(f, ", {:.0}rpm", rpm)?;
+        }
+
+        if let Some(manual) = self.manual {
+            write!(f, ", manual {:.0}%", manual)?;
+        }
+
+        if self.fan_failed {
+            write!(f, ", fan failed")?;
+        }
+
+        Ok(())
+    }
+}
 
 /// Temperature monitor.
 pub struct Monitor {
     poll_delay: Duration,
-    fan: Fan
+    config: Config,
+    controller: Controller,
+    last_poll: Option<Instant>,
+    sensor: Box<dyn TemperatureSource>,
+    hysteresis: Option<Hysteresis>,
+    slew: Option<SlewLimiter>,
+    fan: Box<dyn FanDriver>,
+    stall: Option<StallDetector>,
+    temp: Option<f32>,
+    failures: u32,
+    rpm: Option<f32>,
+    logged_duty: Option<f32>,
+    signals: Signals,
+    notifier: Notifier,
+    metrics: Metrics,
+    control: Control,
+    history: History,
+    last_save: Instant,
+    ready: bool
 }
 
 impl Monitor {
     /// Created monitor.
     ///
-    /// Specify the fan speed control pin and loop cycle(secs).
+    /// Specify the fan, curve and loop cycle(secs) through a
+    /// validated configuration, the sensor and the fan driver
+    /// are opened as configured.
     ///
     /// #Example
     ///
     /// ```
-    /// Monitor::new(12, 5000).unwrap();
+    /// Monitor::builder(Config::default()).unwrap();
     /// // panic or ok
     /// ```
     #[rustfmt::skip]
-    pub fn builder(pin: u8, delay: u64) -> Result<Self> {
+    pub fn builder(config: Config) -> Result<Self> {
+        let sensor = temp::open(&config.sensor)?;
+        let fan = driver::open(&config.fan)?;
+        let history = history::open(&config.history);
+        Ok(Self::new(config, sensor, fan)?.with_history(history))
+    }
+
+    /// Created monitor with the given sensor and fan driver.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let recording = Recording::default();
+    /// let mut monitor = Monitor::new(
+    ///     Config::default(),
+    ///     Box::new(Script::new(vec![50.0])),
+    ///     Box::new(recording.clone())
+    /// ).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(
+        config: Config, 
+        sensor: Box<dyn TemperatureSource>, 
+        fan: Box<dyn FanDriver>
+    ) -> Result<Self> {
+        let control = Control::default();
+        let history = History::new(config.history.capacity);
+        control.set_config(config.clone());
+        control.set_history(history.clone());
         Ok(Self {
-            poll_delay: Duration::from_secs(delay),
-            fan: Fan::new(pin)?
+            poll_delay: Duration::from_secs(config.poll_interval),
+            controller: Controller::new(&config)?,
+            last_poll: None,
+            hysteresis: hysteresis(&config),
+            slew: slew(&config),
+            stall: stall(&config),
+            sensor,
+            fan,
+            config,
+            temp: None,
+            failures: 0,
+            rpm: None,
+            logged_duty: None,
+            signals: Signals::default(),
+            notifier: Notifier::disabled(),
+            metrics: Metrics::default(),
+            control,
+            history,
+            last_save: Instant::now(),
+            ready: false
         })
     }
 
+    /// Report readiness, watchdog pings and status to systemd.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let monitor = Monitor::builder(Config::default())
+    ///     .unwrap()
+    ///     .with_notifier(Notifier::from_env().unwrap());
+    /// ```
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Record into a history, e.g. one restored from its file.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let monitor = Monitor::builder(Config::default())
+    ///     .unwrap()
+    ///     .with_history(History::new(100));
+    /// ```
+    pub fn with_history(mut self, history: History) -> Self {
+        self.control.set_history(history.clone());
+        self.history = history;
+        self
+    }
+
+    /// Stop or reload the monitor as `signals` request.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let signals = Signals::default();
+    /// let monitor = Monitor::builder(Config::default())
+    ///     .unwrap()
+    ///     .with_signals(signals.clone());
+    /// signals.request(Signal::Terminate);
+    /// monitor.run().unwrap();
+    /// ```
+    pub fn with_signals(mut self, signals: Signals) -> Self {
+        self.signals = signals;
+        self
+    }
+
+    /// Swap in a new configuration while the fan keeps running.
+    ///
+    /// Everything that can fail is built from the new configuration
+    /// before anything is replaced, so a rejected configuration leaves
+    /// the monitor as it was. The controller, hysteresis, ramp and
+    /// stall state only restart when their settings changed, the sensor
+    /// and the fan driver are only reopened when theirs did.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut monitor = Monitor::builder(Config::default()).unwrap();
+    /// let config = Config::parse("poll_interval = 5").unwrap();
+    /// monitor.reload(config).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn reload(&mut self, config: Config) -> Result<()> {
+        config.validate()?;
+        self.notifier.check(&config)?;
+        let old = &self.config;
+        let controller = match (config.mode, &config.curve, &config.pid) == (old.mode, &old.curve, &old.pid) {
+            true => None,
+            false => Some(Controller::new(&config)?)
+        };
+
+        let sensor = match config.sensor == old.sensor {
+            true => None,
+            false => Some(temp::open(&config.sensor)?)
+        };
+
+        let ramp = (config.fan.ramp_up, config.fan.ramp_down) != (old.fan.ramp_up, old.fan.ramp_down);
+        let hysteresis_changed = config.hysteresis != old.hysteresis;
+        let stall_changed = config.fan.stall != old.fan.stall;
+        let capacity_changed = config.history.capacity != old.history.capacity;
+        if !config.fan.same_backend(&old.fan) {
+            self.reopen(&config)?;
+        }
+
+        if let Some(controller) = controller {
+            self.controller = controller;
+        }
+
+        if let Some(sensor) = sensor {
+            self.sensor = sensor;
+        }
+
+        if hysteresis_changed {
+            self.hysteresis = hysteresis(&config);
+        }
+
+        if ramp {
+            let percent = self.fan.get_duty() as f32 * 100.0 / config.fan.range as f32;
+            self.slew = slew(&config);
+            if let Some(slew) = &mut self.slew {
+                slew.set(percent);
+            }
+        }
+
+        if stall_changed {
+            self.stall = stall(&config);
+        }
+
+        if capacity_changed {
+            self.history.set_capacity(config.history.capacity);
+        }
+
+        self.poll_delay = Duration::from_secs(config.poll_interval);
+        log::configure(&config.log);
+        self.control.set_config(config.clone());
+        self.config = config;
+        Ok(())
+    }
+
+    /// Replace the fan driver, keeping the duty(%).
+    ///
+    /// The new driver is opened while the old one keeps the fan
+    /// running, and only once it opened is the old one stopped and
+    /// shut down. When both claim the same hardware the old one has
+    /// to let go first, it is shut down at its duty and dropped and,
+    /// should the new one fail to open, opened again. Only when that
+    /// fails too is the fan left at its duty with a closed driver,
+    /// every new duty then fails until a reload opens a driver again.
+    #[rustfmt::skip]
+    fn reopen(&mut self, config: &Config) -> Result<()> {
+        let old = &self.config.fan;
+        let percent = self.fan.get_duty() as f32 * 100.0 / old.range as f32;
+        if !config.fan.shares_hardware(old) {
+            let fan = driver::open(&config.fan)?;
+            let mut previous = std::mem::replace(&mut self.fan, fan);
+            if let Err(e) = previous.set_duty(0).and_then(|_| previous.shutdown()) {
+                warn!("cannot stop the previous fan driver: {:#}", e);
+            }
+
+            return self.fan.set_duty(to_pwm(percent, config.fan.range))
+        }
+
+        // the old driver is gone before the new one claims its hardware.
+        let closed = Box::new(Closed::new(self.fan.get_duty()));
+        let mut previous = std::mem::replace(&mut self.fan, closed);
+        if let Err(e) = previous.shutdown() {
+            self.fan = previous;
+            return Err(e)
+        }
+
+        drop(previous);
+        match driver::open(&config.fan) {
+            Ok(fan) => {
+                self.fan = fan;
+                self.fan.set_duty(to_pwm(percent, config.fan.range))
+            },
+            Err(e) => {
+                match driver::open(old) {
+                    Ok(fan) => {
+                        self.fan = fan;
+                        self.fan.set_duty(to_pwm(percent, old.range))?;
+                    },
+                    Err(reopen) => error!("cannot reopen the previous fan driver either: {:#}", reopen)
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Reload the configuration from its file, an invalid
+    /// configuration is logged and the current one kept.
+    #[rustfmt::skip]
+    fn reload_file(&mut self) {
+        self.send("RELOADING=1");
+        let result = Config::load().and_then(|config| match config {
+            Some(config) => self.reload(config),
+            None => Ok(())
+        });
+
+        match result {
+            Ok(()) => info!("configuration reloaded"),
+            Err(e) => error!("cannot reload the configuration, keeping the current one: {:#}", e)
+        }
+
+        self.send("READY=1");
+    }
+
     /// Monitor execution.
     ///
     /// Get the soc temperature and submit the duty cycle to the PWM pin.
+    /// The duty comes from the curve or the PID controller, with 
+    /// hysteresis configured it only applies while the fan is in 
+    /// the running state. A duty below `fan.min_duty` is switched off
+    /// or raised to it, and a starting fan gets a full duty kickstart.
+    /// With ramp rates configured the duty moves towards its target
+    /// step by step until the next poll.
+    /// If the temperature cannot be read, the duty is held until
+    /// `failsafe.failures` consecutive reads failed, then the failsafe
+    /// policy applies the failsafe duty, holds the duty, or applies
+    /// the failsafe duty and returns the error. Until the first
+    /// reading there's no duty to hold, failed reads apply the
+    /// failsafe duty right away.
+    ///
+    /// With stall detection the fan speed reached since the last poll
+    /// is checked against the duty applied then, before the new duty.
     ///
     /// #Example
     ///
     /// ```
-    /// let mut monitor = Monitor::new(12, 5000).unwrap();
+    /// let mut monitor = Monitor::builder(Config::default()).unwrap();
     /// loop { monitor.poll().unwrap() }
     /// ```
     #[rustfmt::skip]
     pub fn poll(&mut self) -> Result<()> {
-        self.fan.up(get_pwm(get_temp()?))?;
-        sleep(self.poll_delay);
+        let started = Instant::now();
+        let range = self.config.fan.range;
+        self.rpm = self.fan.rpm()?;
+        self.check_stall()?;
+
+        let failsafe = &self.config.failsafe;
+        let temp = match self.sensor.read() {
+            Ok(Celsius(temp)) => temp,
+            Err(e) => {
+                self.temp = None;
+                self.failures += 1;
+                self.metrics.sensor_error(&e);
+                warn!(
+                    [FAILURES = self.failures, ERROR = e.kind()],
+                    "cannot read the temperature ({}/{}): {}", 
+                    self.failures, failsafe.failures, e
+                );
+
+                if self.failures == failsafe.failures {
+                    self.metrics.failsafe();
+                    let action = match failsafe.policy {
+                        FailsafePolicy::Duty => format!("running the fan at {}%", failsafe.duty),
+                        FailsafePolicy::Hold => "holding the duty".to_string(),
+                        FailsafePolicy::Exit => format!("running the fan at {}% and exiting", failsafe.duty)
+                    };
+
+                    error!(
+                        [FAILURES = self.failures, POLICY = log::name(&failsafe.policy)],
+                        "temperature lost, failsafe {}", action
+                    );
+                }
+
+                // before the first reading there's no duty to hold,
+                // the fan runs at the failsafe duty from the start.
+                let engaged = self.failures >= failsafe.failures;
+                let unread = self.last_poll.is_none();
+                if unread || (engaged && failsafe.policy != FailsafePolicy::Hold) {
+                    self.fan.set_duty(to_pwm(failsafe.duty, range))?;
+                    self.logged_duty = Some(failsafe.duty);
+                    if let Some(slew) = &mut self.slew {
+                        slew.set(failsafe.duty);
+                    }
+                }
+
+                if engaged && failsafe.policy == FailsafePolicy::Exit {
+                    return Err(e.into())
+                }
+
+                self.publish(None, started.elapsed());
+                self.notify();
+                self.signals.wait(self.poll_delay);
+                return Ok(())
+            }
+        };
+
+        if self.failures >= failsafe.failures {
+            info!(
+                [FAILURES = self.failures],
+                "temperature readings are back after {} failures, leaving failsafe", self.failures
+            );
+        }
+
+        debug!([TEMP_C = log::tenths(temp)], "temperature {:.1}°C", temp);
+        self.failures = 0;
+        self.temp = Some(temp);
+        let now = Instant::now();
+        let elapsed = self.last_poll
+            .map(|last| now.saturating_duration_since(last))
+            .unwrap_or_default();
+        self.last_poll = Some(now);
+
+        let duty = match &mut self.controller {
+            Controller::Curve(curve) => curve.duty(temp),
+            Controller::Pid(pid) => pid.update(temp, elapsed)
+        };
+
+        let (running, floor) = match (&mut self.hysteresis, &self.config.hysteresis) {
+            (Some(hysteresis), Some(config)) => (
+                hysteresis.update(temp, now),
+                config.min_duty.max(self.config.fan.min_duty)
+            ),
+            _ => (true, 0.0)
+        };
+
+        let target = match self.control.manual(now) {
+            Some(manual) => self.safe_floor(manual.duty, temp),
+            None if running => duty.max(floor),
+            None => 0.0
+        };
+
+        self.log_duty(target, temp);
+        let deadline = now + self.poll_delay;
+        self.step(target)?;
+        self.publish(Some(target), started.elapsed());
+        self.notify();
+        self.ramp(target, deadline)
+    }
+
+    /// Share the state a poll left with the metrics, the control
+    /// socket and the history.
+    #[rustfmt::skip]
+    fn publish(&mut self, target: Option<f32>, latency: Duration) {
+        let status = self.status();
+        self.history.record(Sample::now(status.temp, status.duty, status.rpm));
+        self.metrics.poll(status.clone(), target, latency);
+        self.control.set_status(status);
+        self.save_history(false);
+    }
+
+    /// Save the history to `history.file` every `save_interval`,
+    /// or right away when forced. A failure is only logged.
+    #[rustfmt::skip]
+    fn save_history(&mut self, force: bool) {
+        let config = &self.config.history;
+        let interval = Duration::from_secs(config.save_interval);
+        if let Some(file) = &config.file {
+            if force || self.last_save.elapsed() >= interval {
+                self.last_save = Instant::now();
+                if let Err(e) = self.history.save(file) {
+                    warn!("cannot save the history: {:#}", e);
+                }
+            }
+        }
+    }
+
+    /// Log the target duty(%) when it moved by 1% or more since
+    /// it was last logged.
+    #[rustfmt::skip]
+    fn log_duty(&mut self, target: f32, temp: f32) {
+        match self.logged_duty {
+            Some(logged) if (logged - target).abs() < 1.0 => return,
+            Some(logged) => info!(
+                [TEMP_C = log::tenths(temp), DUTY = log::tenths(target)],
+                "duty {:.0}% -> {:.0}% at {:.1}°C", logged, target, temp
+            ),
+            None => info!(
+                [TEMP_C = log::tenths(temp), DUTY = log::tenths(target)],
+                "duty {:.0}% at {:.1}°C", target, temp
+            )
+        }
+
+        self.logged_duty = Some(target);
+    }
+
+    /// Raise a manual duty(%) to `control.safe_duty` at or above
+    /// `control.critical_temp`.
+    #[rustfmt::skip]
+    fn safe_floor(&self, duty: f32, temp: f32) -> f32 {
+        match &self.config.control {
+            Some(control) if temp >= control.critical_temp => duty.max(control.safe_duty),
+            _ => duty
+        }
+    }
+
+    /// Drive the fan towards a duty(%) until `deadline`.
+    ///
+    /// Without ramp rates the duty was applied at once, otherwise a
+    /// ticker steps it every `fan.ramp_tick`, so a long poll delay
+    /// still gives a smooth ramp.
+    #[rustfmt::skip]
+    fn ramp(&mut self, target: f32, deadline: Instant) -> Result<()> {
+        let tick = Duration::from_millis(self.config.fan.ramp_tick);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return Ok(())
+            }
+
+            let wait = if self.slew.is_some() { left.min(tick) } else { left };
+            if self.signals.wait(wait) {
+                return Ok(())
+            }
+
+            self.step(target)?;
+        }
+    }
+
+    /// Move the fan one ramp step towards a duty(%).
+    #[rustfmt::skip]
+    fn step(&mut self, target: f32) -> Result<()> {
+        let duty = match &mut self.slew {
+            Some(slew) => slew.step(target, Instant::now()),
+            None => target
+        };
+
+        self.apply(duty)
+    }
+
+    /// Tell systemd how the loop is doing.
+    ///
+    /// The first completed poll sends `READY=1`, and every one sends
+    /// `WATCHDOG=1` and the status, whether it read the temperature
+    /// or not: a lost sensor is the failsafe's business, restarting
+    /// would only stop the fan and start the failed reads over. Only
+    /// a sensor or a driver hanging the loop lets the watchdog
+    /// restart the service.
+    #[rustfmt::skip]
+    fn notify(&mut self) {
+        let mut message = String::new();
+        if !self.ready {
+            self.ready = true;
+            message.push_str("READY=1\n");
+        }
+
+        message.push_str("WATCHDOG=1\n");
+        message.push_str(&format!("STATUS={}", self.status()));
+        self.send(&message);
+    }
+
+    /// Send a notification, a failure is only logged.
+    fn send(&mut self, message: &str) {
+        if let Err(e) = self.notifier.send(message) {
+            warn!("{:#}", e);
+        }
+    }
+
+    /// Submit a duty(%) to the fan, kicking it when it starts.
+    #[rustfmt::skip]
+    fn apply(&mut self, duty: f32) -> Result<()> {
+        let fan = &self.config.fan;
+        let duty = to_pwm(min_running(duty, fan.min_duty, fan.below_min), fan.range);
+        let previous = self.fan.get_duty();
+        if previous == 0 && duty > 0 && fan.kickstart > 0 {
+            self.kick(duty, Duration::from_millis(fan.kickstart))?;
+        } else if duty != previous {
+            self.fan.set_duty(duty)?;
+        }
+
         Ok(())
     }
-    
+
+    /// Judge the fan speed against the duty applied.
+    ///
+    /// A stalled fan gets a full-power kick for `kick_duration`
+    /// and goes back to its duty, a fan failed after every kick 
+    /// is logged and reported to the alert command, and so is 
+    /// its recovery.
+    #[rustfmt::skip]
+    fn check_stall(&mut self) -> Result<()> {
+        let (detector, rpm) = match (&mut self.stall, self.rpm) {
+            (Some(detector), Some(rpm)) => (detector, rpm),
+            _ => return Ok(())
+        };
+
+        let config = match &self.config.fan.stall {
+            Some(config) => config,
+            None => return Ok(())
+        };
+
+        let range = self.config.fan.range;
+        let duty = self.fan.get_duty();
+        let percent = duty as f32 * 100.0 / range as f32;
+        let event = match detector.check(percent, rpm) {
+            Verdict::Kick => {
+                warn!(
+                    [DUTY = log::tenths(percent), RPM = log::tenths(rpm)],
+                    "fan stalled at {:.0}% duty and {:.0}rpm, kicking at full power", percent, rpm
+                );
+                let kick_duration = Duration::from_millis(config.kick_duration);
+                return self.kick(duty, kick_duration)
+            },
+            Verdict::Failed => {
+                error!(
+                    [DUTY = log::tenths(percent), RPM = log::tenths(rpm)],
+                    "fan failed: {:.0}rpm at {:.0}% duty after {} kicks", rpm, percent, config.kicks
+                );
+                "fan_failed"
+            },
+            Verdict::Recovered => {
+                info!([DUTY = log::tenths(percent), RPM = log::tenths(rpm)], "fan recovered: {:.0}rpm at {:.0}% duty", rpm, percent);
+                "fan_recovered"
+            },
+            Verdict::Ok | Verdict::StillFailed => return Ok(())
+        };
+
+        if let Some(command) = &config.alert_command {
+            stall::alert(command, event, percent, rpm);
+        }
+
+        Ok(())
+    }
+
+    /// Run the fan at full duty for a while, then at `duty`.
+    #[rustfmt::skip]
+    fn kick(&mut self, duty: u32, duration: Duration) -> Result<()> {
+        self.fan.set_duty(self.config.fan.range)?;
+        self.signals.wait(duration);
+        self.fan.set_duty(duty)
+    }
+
+    /// Manual override and state shared with the control socket.
+    pub fn control(&self) -> Control {
+        self.control.clone()
+    }
+
+    /// Metrics the monitor records into.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Current state of the monitor.
+    #[rustfmt::skip]
+    pub fn status(&self) -> Status {
+        let now = Instant::now();
+        let manual = self.control.manual(now);
+        Status {
+            temp: self.temp,
+            duty: self.fan.get_duty() as f32 * 100.0 / self.config.fan.range as f32,
+            rpm: self.rpm,
+            fan_failed: self.stall
+                .as_ref()
+                .map(|stall| stall.failed())
+                .unwrap_or(false),
+            sensor_failures: self.failures,
+            manual: manual.map(|manual| manual.duty),
+            manual_expires: manual
+                .and_then(|manual| manual.until)
+                .map(|until| until.saturating_duration_since(now).as_secs())
+        }
+    }
+
     /// Running monitor in independent thread.
     /// 
+    /// The loop ends on error or when a stop is requested, a reload
+    /// request re-reads the configuration in between two polls.
+    /// A stopped monitor leaves the fan at `shutdown.duty`, either 
+    /// way the fan driver is shut down before returning.
+    ///
     /// #Example
     ///
     /// ```
-    /// Monitor::new(12, 5000)
+    /// Monitor::builder(Config::default())
     ///     .unwrap()
     ///     .run()
     ///     .unwrap();
     /// ```
     #[rustfmt::skip]
-    #[allow(unreachable_code)]
     pub fn run(self) -> Result<()> {
         spawn(move || {
             let mut this = self;
-            loop { this.poll()? }
-            Ok::<(), anyhow::Error>(())
+            let result = loop {
+                if this.signals.stop().is_some() { break Ok(()) }
+                if this.signals.take_reload() { this.reload_file() }
+                if let Err(e) = this.poll() { break Err(e) }
+            };
+
+            this.send("STOPPING=1");
+            this.save_history(true);
+            match &result {
+                Ok(()) => {
+                    let (range, duty) = (this.config.fan.range, this.config.shutdown.duty);
+                    info!([DUTY = log::tenths(duty)], "stopping, leaving the fan at {}%", duty);
+                    this.fan.set_duty(to_pwm(duty, range))?;
+                },
+                Err(e) => error!("stopping on error: {:#}", e)
+            }
+
+            this.fan.shutdown()?;
+            result
         })
         .join()
         .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp::Script;
+    use crate::testutil::{
+        notify_socket,
+        receive,
+        scratch,
+        tracked_tach
+    };
+    use crate::signal::Signal;
+    use crate::driver::{
+        Record,
+        Recording,
+        WithTach
+    };
+    use crate::tach::{
+        Tach,
+        Tachometer
+    };
+    use crate::config::{
+        FanConfig,
+        FanDriverKind,
+        BelowMin,
+        HysteresisConfig,
+        StallConfig
+    };
+
+    /// Config polling without delay into the memory driver.
+    fn config() -> Config {
+        Config {
+            poll_interval: 0,
+            fan: FanConfig {
+                driver: FanDriverKind::Memory,
+                ..FanConfig::default()
+            },
+            ..Config::default()
+        }
+    }
+
+    /// Monitor reading `readings` into a recording.
+    fn monitor(config: Config, readings: Vec<f32>) -> (Monitor, Recording) {
+        let recording = Recording::default();
+        let monitor = Monitor::new(
+            config,
+            Box::new(Script::new(readings)),
+            Box::new(recording.clone())
+        ).unwrap();
+        (monitor, recording)
+    }
+
+    #[test]
+    fn polls_the_curve_into_the_fan() {
+        let nan = f32::NAN;
+        let (mut monitor, recording) = monitor(config(), vec![30.0, 50.0, 60.0, 70.0, nan, nan, nan, 45.0]);
+        for _ in 0..4 {
+            monitor.poll().unwrap();
+        }
+
+        // off below the curve, then 50% and 100%, held at 70°C.
+        assert_eq!(recording.record().history, vec![128, 255]);
+        assert_eq!(monitor.status().temp, Some(70.0));
+
+        // two failed reads hold the duty, the third applies the failsafe.
+        monitor.poll().unwrap();
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128, 255]);
+        assert_eq!(monitor.status().sensor_failures, 2);
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128, 255, 255]);
+
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128, 255, 255, 64]);
+        assert_eq!(monitor.status().sensor_failures, 0);
+        assert!(!recording.record().shutdown);
+    }
+
+    #[test]
+    fn holds_a_floor_while_running() {
+        let mut config = config();
+        config.hysteresis = Some(HysteresisConfig { on: 50.0, off: 40.0, min_dwell: 0, min_duty: 20.0 });
+        let (mut monitor, recording) = monitor(config, vec![45.0, 52.0, 42.0, 38.0]);
+        for _ in 0..4 {
+            monitor.poll().unwrap();
+        }
+
+        // stopped until 50°C, then 60% and the 20% floor until 40°C.
+        assert_eq!(recording.record().history, vec![153, 51, 0]);
+    }
+
+    #[test]
+    fn kicks_the_fan_when_it_starts() {
+        let config = Config {
+            fan: FanConfig {
+                min_duty: 30.0,
+                below_min: BelowMin::Min,
+                kickstart: 50,
+                ..config().fan
+            },
+            ..config()
+        };
+        let (mut monitor, recording) = monitor(config, vec![30.0, 45.0, 50.0, 30.0, 45.0, 42.0]);
+        monitor.poll().unwrap();
+        assert!(recording.record().history.is_empty());
+
+        // 25% at 45°C is raised to the 30% minimum, after a full-power kick.
+        let started = Instant::now();
+        monitor.poll().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(recording.record().history, vec![255, 77]);
+
+        // a running fan isn't kicked, a stopped one is again.
+        for _ in 0..3 {
+            monitor.poll().unwrap();
+        }
+        assert_eq!(recording.record().history, vec![255, 77, 128, 0, 255, 77]);
+
+        // below the minimum and above 0 it stays at the minimum.
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![255, 77, 128, 0, 255, 77]);
+    }
+
+    #[test]
+    fn stops_the_fan_below_the_minimum() {
+        let config = Config {
+            fan: FanConfig {
+                min_duty: 30.0,
+                kickstart: 1,
+                ..config().fan
+            },
+            ..config()
+        };
+        let (mut monitor, recording) = monitor(config, vec![45.0, 50.0, 45.0]);
+        for _ in 0..3 {
+            monitor.poll().unwrap();
+        }
+
+        assert_eq!(recording.record().history, vec![255, 128, 0]);
+    }
+
+    /// Wait until the alert command wrote a line.
+    fn alerted(path: &std::path::Path, line: &str) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_secs(5) {
+            let text = std::fs::read_to_string(path).unwrap_or_default();
+            if text.lines().any(|l| l == line) {
+                return true
+            }
+
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        false
+    }
+
+    #[test]
+    fn kicks_a_stalled_fan_then_reports_it_failed() {
+        let alerts = scratch("monitor-stall").join("alerts");
+        let config = Config {
+            fan: FanConfig {
+                stall: Some(StallConfig {
+                    kicks: 2,
+                    kick_duration: 1,
+                    alert_command: Some(format!(
+                        "echo $RADIATOR_EVENT $RADIATOR_DUTY $RADIATOR_RPM >> {}", alerts.display()
+                    )),
+                    ..StallConfig::default()
+                }),
+                ..config().fan
+            },
+            ..config()
+        };
+
+        // stopped for four polls, then spinning fast.
+        let recording = Recording::default();
+        let tach = Tach::new(
+            Box::new(crate::tach::Script::new(vec![0.0, 0.0, 0.0, 0.0, 60_000.0], 2)),
+            Tachometer::new(2, Duration::from_secs(1))
+        );
+        let mut monitor = Monitor::new(
+            config,
+            Box::new(Script::new(vec![50.0])),
+            Box::new(WithTach::new(Box::new(recording.clone()), tach))
+        ).unwrap();
+
+        // the fan isn't asked to spin before the first poll.
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128]);
+
+        // two kicks at full power, each back to 50%.
+        monitor.poll().unwrap();
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128, 255, 128, 255, 128]);
+        assert!(!monitor.status().fan_failed);
+
+        monitor.poll().unwrap();
+        assert_eq!(recording.record().history, vec![128, 255, 128, 255, 128]);
+        assert!(monitor.status().fan_failed);
+        assert!(alerted(&alerts, "fan_failed 50 0"));
+
+        std::thread::sleep(Duration::from_millis(20));
+        monitor.poll().unwrap();
+        assert!(!monitor.status().fan_failed);
+        assert!(alerted(&alerts, "fan_recovered 50 60000"));
+    }
+
+    /// Fake `/sys/class/pwm` with `pwmchip0/pwm0` already exported.
+    fn sysfs(name: &str) -> std::path::PathBuf {
+        let root = scratch(&format!("monitor-{}", name));
+        std::fs::create_dir_all(root.join("pwmchip0/pwm0")).unwrap();
+        std::fs::write(root.join("pwmchip0/pwm0/enable"), "0").unwrap();
+        root
+    }
+
+    fn read(path: std::path::PathBuf) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn keeps_the_old_driver_when_the_new_one_fails() {
+        let (mut monitor, recording) = monitor(config(), vec![50.0, 60.0]);
+        monitor.poll().unwrap();
+
+        let new = Config::parse("poll_interval = 1\n[fan]\ndriver = \"sysfs\"\nsysfs_root = \"/nonexistent\"").unwrap();
+        assert!(monitor.reload(new).is_err());
+        assert_eq!(monitor.config.fan.driver, FanDriverKind::Memory);
+
+        // the old driver was neither stopped nor replaced.
+        monitor.poll().unwrap();
+        assert_eq!(recording.record(), Record { history: vec![128, 255], shutdown: false });
+    }
+
+    #[test]
+    fn stops_the_old_driver_once_the_new_one_opened() {
+        let (mut monitor, recording) = monitor(config(), vec![50.0]);
+        monitor.poll().unwrap();
+
+        let new = Config::parse("poll_interval = 1\n[fan]\ndriver = \"memory\"\nrange = 100").unwrap();
+        monitor.reload(new).unwrap();
+        assert_eq!(recording.record(), Record { history: vec![128, 0], shutdown: true });
+        assert_eq!(monitor.fan.get_duty(), 51);
+    }
+
+    #[test]
+    fn reopens_a_shared_channel_when_the_new_driver_fails() {
+        let root = sysfs("reopen");
+        let text = format!("poll_interval = 1\n[fan]\ndriver = \"sysfs\"\nsysfs_root = {:?}\n", root);
+        let old = Config { poll_interval: 0, ..Config::parse(&text).unwrap() };
+        let fan = driver::open(&old.fan).unwrap();
+        let mut monitor = Monitor::new(old, Box::new(Script::new(vec![50.0])), fan).unwrap();
+        monitor.poll().unwrap();
+        assert_eq!(read(root.join("pwmchip0/pwm0/duty_cycle")), "20078");
+
+        // the channel opens, the tach line doesn't.
+        let new = format!("{}frequency = 1000\n[fan.tach]\npin = 6\nchip = \"/nonexistent\"", text);
+        assert!(monitor.reload(Config::parse(&new).unwrap()).is_err());
+        assert_eq!(read(root.join("pwmchip0/pwm0/period")), "40000");
+        assert_eq!(read(root.join("pwmchip0/pwm0/duty_cycle")), "20078");
+        assert_eq!(read(root.join("pwmchip0/pwm0/enable")), "1");
+
+        monitor.poll().unwrap();
+        assert_eq!(monitor.fan.get_duty(), 128);
+    }
+
+    #[test]
+    fn releases_the_tach_before_opening_the_new_one() {
+        let tach = "[fan.tach]\npin = 6\nsource = \"script\"\nscript = [1200.0]";
+        let old = Config {
+            poll_interval: 0,
+            ..Config::parse(&format!("[fan]\ndriver = \"memory\"\n{}", tach)).unwrap()
+        };
+        let recording = Recording::default();
+        let (held, closed) = tracked_tach();
+        let fan = WithTach::new(Box::new(recording.clone()), held);
+        let mut monitor = Monitor::new(old, Box::new(Script::new(vec![50.0])), Box::new(fan)).unwrap();
+        monitor.poll().unwrap();
+        assert_eq!(monitor.status().rpm, Some(0.0));
+
+        // another backend, the same tach line.
+        let root = sysfs("tach");
+        let new = format!("[fan]\ndriver = \"sysfs\"\nsysfs_root = {:?}\n{}", root, tach);
+        monitor.reload(Config::parse(&new).unwrap()).unwrap();
+        assert!(closed.load(std::sync::atomic::Ordering::SeqCst));
+        assert_eq!(recording.record(), Record { history: vec![128], shutdown: true });
+        assert_eq!(read(root.join("pwmchip0/pwm0/duty_cycle")), "20078");
+
+        // the new tach counts the pulses from then on.
+        monitor.fan.rpm().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(monitor.fan.rpm().unwrap(), Some(1200.0));
+    }
+
+    #[test]
+    fn hands_a_shared_channel_over_at_its_duty() {
+        let root = sysfs("handover");
+        let text = format!("poll_interval = 1\n[fan]\ndriver = \"sysfs\"\nsysfs_root = {:?}\n", root);
+        let old = Config { poll_interval: 0, ..Config::parse(&text).unwrap() };
+        let fan = driver::open(&old.fan).unwrap();
+        let mut monitor = Monitor::new(old, Box::new(Script::new(vec![50.0])), fan).unwrap();
+        monitor.poll().unwrap();
+
+        let new = format!("{}frequency = 1000", text);
+        monitor.reload(Config::parse(&new).unwrap()).unwrap();
+        assert_eq!(read(root.join("pwmchip0/pwm0/period")), "1000000");
+        assert_eq!(read(root.join("pwmchip0/pwm0/duty_cycle")), "501960");
+        assert_eq!(read(root.join("pwmchip0/pwm0/enable")), "1");
+    }
+
+    #[test]
+    fn stops_at_the_shutdown_duty_on_sigterm() {
+        let mut config = config();
+        config.poll_interval = 10;
+        config.shutdown.duty = 40.0;
+        let (monitor, recording) = monitor(config, vec![50.0]);
+        let signals = Signals::default();
+        let monitor = monitor.with_signals(signals.clone());
+
+        let watcher = recording.clone();
+        let terminate = spawn(move || {
+            while watcher.record().history.is_empty() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            signals.request(Signal::Terminate);
+        });
+
+        // the request cuts the 10s poll delay short.
+        let started = Instant::now();
+        monitor.run().unwrap();
+        terminate.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // the duty is written before the driver shuts down, which
+        // refuses duties afterwards.
+        let record = recording.record();
+        assert_eq!(record.history, vec![128, 102]);
+        assert!(record.shutdown);
+    }
+
+    #[test]
+    fn stops_before_the_first_poll() {
+        let (monitor, recording) = monitor(config(), vec![50.0]);
+        let signals = Signals::default();
+        signals.request(Signal::Terminate);
+        monitor.with_signals(signals).run().unwrap();
+        assert_eq!(recording.record(), Record { history: vec![255], shutdown: true });
+    }
+
+    #[test]
+    fn shuts_down_without_the_shutdown_duty_on_error() {
+        let mut config = config();
+        config.failsafe.policy = FailsafePolicy::Exit;
+        config.failsafe.failures = 1;
+        config.failsafe.duty = 60.0;
+        let (monitor, recording) = monitor(config, vec![f32::NAN]);
+        assert!(monitor.run().is_err());
+        assert_eq!(recording.record(), Record { history: vec![153], shutdown: true });
+    }
+
+    #[test]
+    fn pings_the_watchdog_at_every_poll() {
+        let (systemd, path) = notify_socket("monitor-notify");
+        let (monitor, _) = monitor(config(), vec![50.0, f32::NAN]);
+        let mut monitor = monitor.with_notifier(Notifier::connect(&path).unwrap());
+        monitor.poll().unwrap();
+        assert_eq!(receive(&systemd), "READY=1\nWATCHDOG=1\nSTATUS=50.0°C, duty 50%");
+        monitor.poll().unwrap();
+        assert_eq!(receive(&systemd), "WATCHDOG=1\nSTATUS=temperature unavailable (1 failed reads), duty 50%");
+    }
+
+    #[test]
+    fn keeps_pinging_and_cooling_without_a_sensor() {
+        let (systemd, path) = notify_socket("monitor-dead-sensor");
+        let (monitor, recording) = monitor(config(), vec![f32::NAN]);
+        let mut monitor = monitor.with_notifier(Notifier::connect(&path).unwrap());
+        monitor.poll().unwrap();
+        assert_eq!(
+            receive(&systemd),
+            "READY=1\nWATCHDOG=1\nSTATUS=temperature unavailable (1 failed reads), duty 100%"
+        );
+
+        for failures in 2..=5 {
+            monitor.poll().unwrap();
+            assert_eq!(receive(&systemd), format!(
+                "WATCHDOG=1\nSTATUS=temperature unavailable ({} failed reads), duty 100%", failures
+            ));
+        }
+
+        // the failsafe duty from the first failed read, never 0.
+        assert_eq!(recording.record().history, vec![255; 5]);
+    }
+
+    #[test]
+    fn runs_the_failsafe_duty_until_the_first_reading() {
+        let mut config = config();
+        config.failsafe.policy = FailsafePolicy::Hold;
+        config.failsafe.duty = 60.0;
+        let (mut monitor, recording) = monitor(config, vec![f32::NAN, 50.0, f32::NAN]);
+        for _ in 0..3 {
+            monitor.poll().unwrap();
+        }
+
+        // once read, the duty is held again.
+        assert_eq!(recording.record().history, vec![153, 128]);
+    }
+
+    #[test]
+    fn exits_after_the_failsafe_duty() {
+        let mut config = config();
+        config.failsafe.policy = FailsafePolicy::Exit;
+        config.failsafe.failures = 1;
+        let (mut monitor, recording) = monitor(config, vec![f32::NAN]);
+        assert!(monitor.poll().is_err());
+        assert_eq!(recording.record().history, vec![255]);
+    }
+}
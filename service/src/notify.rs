@@ -0,0 +1,181 @@
+use std::env;
+use std::time::Duration;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{
+    SocketAddr,
+    UnixDatagram
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use super::config::Config;
+
+/// systemd notification socket.
+///
+/// Speaks the `sd_notify` protocol: every message is one datagram
+/// of `KEY=VALUE` lines sent to `$NOTIFY_SOCKET`, a filesystem path
+/// or an abstract socket when it starts with `@`. Without the
+/// variable, when not started by a `Type=notify` unit, every
+/// message is dropped.
+///
+/// With `WatchdogSec` set systemd passes the interval in
+/// `$WATCHDOG_USEC`, a `WATCHDOG=1` must arrive within it.
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    watchdog: Option<Duration>
+}
+
+impl Notifier {
+    /// Notifier for `$NOTIFY_SOCKET`.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut notifier = Notifier::from_env().unwrap();
+    /// notifier.send("READY=1").unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn from_env() -> Result<Self> {
+        let mut this = match env::var("NOTIFY_SOCKET") {
+            Ok(path) if !path.is_empty() => Self::connect(&path)?,
+            _ => Self::disabled()
+        };
+
+        // the watchdog is meant for another process when the pid differs.
+        let pid = env::var("WATCHDOG_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        if pid.map(|pid| pid == std::process::id()).unwrap_or(true) {
+            this.watchdog = env::var("WATCHDOG_USEC").ok()
+                .and_then(|usec| usec.parse::<u64>().ok())
+                .filter(|usec| *usec > 0)
+                .map(Duration::from_micros);
+        }
+
+        Ok(this)
+    }
+
+    /// Notifier for a socket path, `@name` for an abstract socket.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let listener = UnixDatagram::bind("/tmp/notify.sock").unwrap();
+    /// let mut notifier = Notifier::connect("/tmp/notify.sock").unwrap();
+    /// notifier.send("READY=1").unwrap();
+    /// let mut buf = [0; 64];
+    /// let size = listener.recv(&mut buf).unwrap();
+    /// assert_eq!(&buf[..size], b"READY=1");
+    /// ```
+    #[rustfmt::skip]
+    pub fn connect(path: &str) -> Result<Self> {
+        let address = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name.as_bytes()),
+            None => SocketAddr::from_pathname(path)
+        }.with_context(|| format!("invalid notify socket {:?}", path))?;
+        let socket = UnixDatagram::unbound()?;
+        Ok(Self {
+            socket: Some((socket, address)),
+            watchdog: None
+        })
+    }
+
+    /// Notifier dropping every message.
+    pub fn disabled() -> Self {
+        Self {
+            socket: None,
+            watchdog: None
+        }
+    }
+
+    /// Check that a configuration pings the watchdog often enough.
+    ///
+    /// The monitor pings once per poll, and systemd recommends
+    /// pinging at half the interval at least.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let notifier = Notifier::from_env().unwrap();
+    /// notifier.check(&Config::default()).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn check(&self, config: &Config) -> Result<()> {
+        let watchdog = match self.watchdog {
+            Some(watchdog) => watchdog.as_secs_f64(),
+            None => return Ok(())
+        };
+
+        if config.poll_interval as f64 * 2.0 > watchdog {
+            return Err(anyhow!(
+                "poll_interval {}s is above half of the {}s systemd watchdog, lower it or raise WatchdogSec",
+                config.poll_interval, watchdog
+            ))
+        }
+
+        Ok(())
+    }
+
+    /// Send one message, e.g. `READY=1` or `STATUS=...`.
+    #[rustfmt::skip]
+    pub fn send(&mut self, message: &str) -> Result<()> {
+        if let Some((socket, address)) = &self.socket {
+            socket.send_to_addr(message.as_bytes(), address)
+                .context("cannot notify systemd")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{
+        notify_socket,
+        receive
+    };
+
+    #[test]
+    fn sends_one_datagram_per_message() {
+        let (listener, path) = notify_socket("notify-path");
+        let mut notifier = Notifier::connect(&path).unwrap();
+        notifier.send("READY=1\nSTATUS=45.0°C").unwrap();
+        notifier.send("WATCHDOG=1").unwrap();
+        assert_eq!(receive(&listener), "READY=1\nSTATUS=45.0°C");
+        assert_eq!(receive(&listener), "WATCHDOG=1");
+    }
+
+    #[test]
+    fn sends_to_an_abstract_socket() {
+        let name = format!("radiator-notify-{}", std::process::id());
+        let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = UnixDatagram::bind_addr(&address).unwrap();
+        let mut notifier = Notifier::connect(&format!("@{}", name)).unwrap();
+        notifier.send("READY=1").unwrap();
+        assert_eq!(receive(&listener), "READY=1");
+    }
+
+    #[test]
+    fn reports_a_missing_socket() {
+        let mut notifier = Notifier::connect("/nonexistent/notify.sock").unwrap();
+        assert!(notifier.send("READY=1").is_err());
+        assert!(Notifier::disabled().send("READY=1").is_ok());
+    }
+
+    #[test]
+    fn checks_the_poll_interval_against_the_watchdog() {
+        let notifier = Notifier {
+            socket: None,
+            watchdog: Some(Duration::from_secs(60))
+        };
+
+        let config = |poll_interval| Config { poll_interval, ..Config::default() };
+        assert!(notifier.check(&config(10)).is_ok());
+        assert!(notifier.check(&config(30)).is_ok());
+        assert!(notifier.check(&config(31)).unwrap_err().to_string().contains("poll_interval 31s"));
+        assert!(Notifier::disabled().check(&config(3600)).is_ok());
+    }
+}
@@ -1,7 +1,13 @@
-use std::sync::Once;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering
+};
+
+use std::sync::Mutex;
 use std::os::raw::{
     c_int,
-    c_uint
+    c_uint,
+    c_void
 };
 
 use anyhow::{
@@ -9,14 +15,36 @@ use anyhow::{
     anyhow
 };
 
+use super::driver::FanDriver;
+use super::tach::{
+    Micros,
+    TachInput
+};
+
 /// ```c
 /// #define PI_OUTPUT 1
 /// ```
 const PI_OUTPUT: c_uint = 1;
 
-/// Only need to call gpioInitialise once, 
-/// once is used to run a one-time global initialization.
-static PI_SETUP: Once = Once::new();
+/// ```c
+/// #define PI_INPUT 0
+/// ```
+const PI_INPUT: c_uint = 0;
+
+/// ```c
+/// #define PI_PUD_UP 2
+/// ```
+const PI_PUD_UP: c_uint = 2;
+
+/// ```c
+/// typedef void (*gpioAlertFuncEx_t)(int gpio, int level, uint32_t tick, void *userdata);
+/// ```
+type AlertFunc = extern "C" fn(gpio: c_int, level: c_int, tick: u32, userdata: *mut c_void);
+
+/// gpioInitialise is global to the process, only call it 
+/// when the library is not initialised yet, gpioTerminate 
+/// clears the flag so that the fan can be opened again.
+static PI_SETUP: AtomicBool = AtomicBool::new(false);
 
 #[link(name = "pigpio", kind = "dylib")]
 extern "C" {
@@ -38,6 +66,14 @@ extern "C" {
     /// ```
     fn gpioInitialise() -> c_int;
     /// ```c
+    /// void gpioTerminate(void);
+    /// ````
+    ///
+    /// Terminates the library.
+    /// Call before program exit. This function resets the used 
+    /// DMA channels, releases memory, and terminates any running threads.
+    fn gpioTerminate();
+    /// ```c
     /// int gpioSetMode(unsigned pin, unsigned mode);
     /// ````
     /// 
@@ -80,59 +116,301 @@ extern "C" {
     /// gpioSetMode(22,PI_ALT0);    // Set GPIO22 to alternative mode 0.
     /// ```
     fn gpioPWM(pin: c_uint, value: c_uint) -> c_int;
+    /// ```c
+    /// int gpioSetPWMfrequency(unsigned user_gpio, unsigned frequency);
+    /// ````
+    ///
+    /// Sets the frequency in hertz to be used for the GPIO.
+    /// The selectable frequencies depend upon the sample rate,
+    /// the closest one to the requested frequency is used.
+    ///
+    /// ```no_run
+    /// user_gpio: 0-31
+    /// frequency: >=0
+    /// ```
+    ///
+    /// Returns the numerically closest frequency if OK,
+    /// otherwise PI_BAD_USER_GPIO.
+    fn gpioSetPWMfrequency(pin: c_uint, frequency: c_uint) -> c_int;
+    /// ```c
+    /// int gpioHardwarePWM(unsigned gpio, unsigned PWMfreq, unsigned PWMduty);
+    /// ````
+    ///
+    /// Starts hardware PWM on a GPIO at the specified frequency
+    /// and dutycycle. Frequencies above 30MHz are unlikely to work.
+    ///
+    /// ```no_run
+    /// gpio: see description
+    /// PWMfreq: 0 (off) or 1-125M (1-187.5M for the BCM2711)
+    /// PWMduty: 0 (off) to 1000000 (1M)(fully on)
+    /// ```
+    ///
+    /// Returns 0 if OK, otherwise PI_BAD_GPIO, PI_NOT_HPWM_GPIO,
+    /// PI_BAD_HPWM_DUTY, PI_BAD_HPWM_FREQ, or PI_HPWM_ILLEGAL.
+    ///
+    /// The GPIO must be one of the following:
+    ///
+    /// ```no_run
+    /// 12  PWM channel 0  All models but A and B
+    /// 13  PWM channel 1  All models but A and B
+    /// 18  PWM channel 0  All models
+    /// 19  PWM channel 1  All models but A and B
+    /// ```
+    fn gpioHardwarePWM(pin: c_uint, frequency: c_uint, duty: c_uint) -> c_int;
+    /// ```c
+    /// int gpioSetPullUpDown(unsigned gpio, unsigned pud);
+    /// ````
+    ///
+    /// Sets or clears resistor pull ups or downs on the GPIO.
+    ///
+    /// Returns 0 if OK, otherwise PI_BAD_GPIO or PI_BAD_PUD.
+    fn gpioSetPullUpDown(pin: c_uint, pud: c_uint) -> c_int;
+    /// ```c
+    /// int gpioSetAlertFuncEx(unsigned user_gpio, gpioAlertFuncEx_t f, void *userdata);
+    /// ````
+    ///
+    /// Registers a function to be called (a callback) when the
+    /// specified GPIO changes state, with the level (0 or 1) and
+    /// the tick (microseconds since boot, wraps every 72 minutes).
+    /// Only one function per GPIO, a null function cancels it.
+    ///
+    /// Returns 0 if OK, otherwise PI_BAD_USER_GPIO.
+    fn gpioSetAlertFuncEx(pin: c_uint, f: Option<AlertFunc>, userdata: *mut c_void) -> c_int;
+    /// ```c
+    /// uint32_t gpioTick(void);
+    /// ````
+    ///
+    /// Returns the current system tick.
+    fn gpioTick() -> u32;
+    /// ```c
+    /// int gpioSetPWMrange(unsigned user_gpio, unsigned range);
+    /// ````
+    ///
+    /// Selects the dutycycle range to be used for the GPIO.
+    /// Subsequent calls to gpioPWM will use a dutycycle between 0 (off)
+    /// and range (fully on).
+    ///
+    /// ```no_run
+    /// user_gpio: 0-31
+    /// range: 25-40000
+    /// ```
+    ///
+    /// Returns the real range for the given GPIO's frequency if OK,
+    /// otherwise PI_BAD_USER_GPIO or PI_BAD_DUTYRANGE.
+    fn gpioSetPWMrange(pin: c_uint, range: c_uint) -> c_int;
 }
 
+/// pigpio hardware PWM duty-cycle steps.
+const HARDWARE_RANGE: u64 = 1_000_000;
+
 /// RaspberryPI fan
 pub struct Fan {
-    pin: u8
+    pin: u8,
+    frequency: u32,
+    range: u32,
+    hardware: bool,
+    duty: u32
 }
 
 impl Fan {
-    /// Specify PWM pin to create Fan instance.
+    /// Specify PWM pin, frequency(Hz) and duty-cycle range 
+    /// to create Fan instance.
     /// 
-    /// note: Only supports Hardware PWM! Hardware PWM available 
-    /// on GPIO12, GPIO13, GPIO18, GPIO19.
+    /// Hardware PWM is available on GPIO12, GPIO13, GPIO18, GPIO19
+    /// at any frequency, the duty-cycle range is scaled onto its
+    /// 1000000 steps. Without `hardware` the DMA-timed software PWM 
+    /// is used instead, which works on any GPIO but only at the 
+    /// sample-rate frequencies (800Hz by default) with 25-40000 steps.
     ///
     /// #Example
     ///
     /// ```
-    /// Fan::new(12).unwrap();
+    /// Fan::new(12, 25000, 255, true).unwrap();
     /// // panic or ok
     /// ```
     #[rustfmt::skip]
-    pub fn new(pin: u8) -> Result<Self> {
-        PI_SETUP.call_once(|| {
-            if unsafe { gpioInitialise() } < 0 {
-                panic!();
+    pub fn new(pin: u8, frequency: u32, range: u32, hardware: bool) -> Result<Self> {
+        initialise()?;
+        if !hardware {
+            if unsafe { gpioSetMode(pin as c_uint, PI_OUTPUT) } != 0 {
+                return Err(anyhow!("gpioSetMode failed!"))
             }
-        });
 
-        if unsafe { gpioSetMode(pin as c_uint, PI_OUTPUT) } != 0 {
-            return Err(anyhow!("gpioSetMode failed!"))
+            if unsafe { gpioSetPWMfrequency(pin as c_uint, frequency as c_uint) } < 0 {
+                return Err(anyhow!("gpioSetPWMfrequency failed!"))
+            }
+
+            if unsafe { gpioSetPWMrange(pin as c_uint, range as c_uint) } < 0 {
+                return Err(anyhow!("gpioSetPWMrange failed!"))
+            }
         }
 
-        Ok(Fan {
-            pin
-        })
+        let mut fan = Fan {
+            pin,
+            frequency,
+            range,
+            hardware,
+            duty: 0
+        };
+
+        fan.set_duty(0)?;
+        Ok(fan)
     }
+}
+
+impl FanDriver for Fan {
 
     /// Update PWM pin duty-cycle.
     /// 
-    /// note: dutycycle between 0 (off) and 255 (fully on).
+    /// note: dutycycle between 0 (off) and range (fully on).
     ///
     /// #Example
     ///
     /// ```
-    /// let fan = Fan::new(12).unwrap();
-    /// fan.up(0).unwrap();
+    /// let mut fan = Fan::new(12, 800, 255).unwrap();
+    /// fan.set_duty(0).unwrap();
     /// // panic or ok
     /// ```
     #[rustfmt::skip]
-    pub fn up(&mut self, value: u8) -> Result<()> {
-        if unsafe { gpioPWM(self.pin as c_uint, value as c_uint) } != 0 {
+    fn set_duty(&mut self, value: u32) -> Result<()> {
+        if self.hardware {
+            let duty = value.min(self.range) as u64 * HARDWARE_RANGE / self.range as u64;
+            let pin = self.pin as c_uint;
+            if unsafe { gpioHardwarePWM(pin, self.frequency as c_uint, duty as c_uint) } != 0 {
+                return Err(anyhow!("gpioHardwarePWM failed!"))
+            }
+        } else if unsafe { gpioPWM(self.pin as c_uint, value as c_uint) } != 0 {
             return Err(anyhow!("gpioPWM failed!"))
         }
 
+        self.duty = value;
         Ok(())
     }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Terminate pigpio, the pin keeps its last duty-cycle.
+    #[rustfmt::skip]
+    fn shutdown(&mut self) -> Result<()> {
+        if PI_SETUP.swap(false, Ordering::SeqCst) {
+            unsafe { gpioTerminate() }
+        }
+
+        Ok(())
+    }
+}
+
+/// Initialise pigpio unless it already is.
+#[rustfmt::skip]
+fn initialise() -> Result<()> {
+    if !PI_SETUP.swap(true, Ordering::SeqCst) && unsafe { gpioInitialise() } < 0 {
+        PI_SETUP.store(false, Ordering::SeqCst);
+        return Err(anyhow!("gpioInitialise failed!"))
+    }
+
+    Ok(())
+}
+
+/// Falling edge ticks of an alert, boxed so the callback
+/// keeps a stable address.
+type Ticks = Box<Mutex<Vec<u32>>>;
+
+/// Record falling edges, level 2 is a watchdog timeout.
+extern "C" fn on_alert(_gpio: c_int, level: c_int, tick: u32, userdata: *mut c_void) {
+    if level == 0 {
+        let ticks = unsafe { &*(userdata as *const Mutex<Vec<u32>>) };
+        if let Ok(mut ticks) = ticks.lock() {
+            ticks.push(tick);
+        }
+    }
+}
+
+/// Fan tach read through pigpio alerts.
+///
+/// pigpio samples the GPIO every 5us and calls back with the
+/// tick of every level change.
+pub struct PigpioTach {
+    pin: u8,
+    ticks: Ticks,
+    wraps: u64,
+    last: u32,
+    closed: bool
+}
+
+impl PigpioTach {
+    /// Watch a tach pin, the internal pull-up is enabled for
+    /// the open-collector output.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// PigpioTach::new(6).unwrap();
+    /// // panic or ok
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(pin: u8) -> Result<Self> {
+        initialise()?;
+        if unsafe { gpioSetMode(pin as c_uint, PI_INPUT) } != 0 {
+            return Err(anyhow!("gpioSetMode failed!"))
+        }
+
+        if unsafe { gpioSetPullUpDown(pin as c_uint, PI_PUD_UP) } != 0 {
+            return Err(anyhow!("gpioSetPullUpDown failed!"))
+        }
+
+        let ticks: Ticks = Box::new(Mutex::new(Vec::new()));
+        let userdata = &*ticks as *const Mutex<Vec<u32>> as *mut c_void;
+        if unsafe { gpioSetAlertFuncEx(pin as c_uint, Some(on_alert), userdata) } != 0 {
+            return Err(anyhow!("gpioSetAlertFuncEx failed!"))
+        }
+
+        Ok(Self {
+            pin,
+            ticks,
+            wraps: 0,
+            last: unsafe { gpioTick() },
+            closed: false
+        })
+    }
+
+    /// Extend a 32-bit tick, which wraps every 72 minutes,
+    /// to 64 bits. Ticks must come in order.
+    #[rustfmt::skip]
+    fn extend(&mut self, tick: u32) -> Micros {
+        if tick < self.last {
+            self.wraps += 1;
+        }
+
+        self.last = tick;
+        (self.wraps << 32) | tick as Micros
+    }
+}
+
+impl TachInput for PigpioTach {
+    #[rustfmt::skip]
+    fn edges(&mut self) -> Result<(Vec<Micros>, Micros)> {
+        let ticks = std::mem::take(&mut *self.ticks.lock().unwrap());
+        let edges = ticks.into_iter()
+            .map(|tick| self.extend(tick))
+            .collect();
+        let now = self.extend(unsafe { gpioTick() });
+        Ok((edges, now))
+    }
+
+    /// Unregister the alert, once only: the pin may already
+    /// carry the alert of the tach that replaced this one.
+    fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            unsafe { gpioSetAlertFuncEx(self.pin as c_uint, None, std::ptr::null_mut()) };
+        }
+    }
+}
+
+impl Drop for PigpioTach {
+    fn drop(&mut self) {
+        self.close();
+    }
 }
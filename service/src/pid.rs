@@ -0,0 +1,189 @@
+use std::time::Duration;
+
+/// PID controller holding the soc at a setpoint temperature.
+///
+/// The error is `temperature - setpoint`, so a hotter soc asks
+/// for more duty. The output is duty(%) clamped to `min..=max`.
+///
+/// * anti-windup: the integral only accumulates while the output
+///   is not saturated, or when the error pulls it back into range,
+///   and the integral term alone never exceeds the output range.
+/// * the derivative is taken on the measurement instead of the
+///   error, so setpoint changes don't kick, and is smoothed by a
+///   first-order low-pass filter with time constant `filter`.
+///
+/// The controller is pure: no clock and no I/O, the caller passes
+/// the elapsed time with every sample.
+#[derive(Debug, Clone)]
+pub struct Pid {
+    setpoint: f32,
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    filter: f32,
+    min: f32,
+    max: f32,
+    integral: f32,
+    derivative: f32,
+    last_temp: Option<f32>
+}
+
+impl Pid {
+    /// Create a controller.
+    ///
+    /// `filter` is the derivative low-pass time constant(secs),
+    /// `min` and `max` bound the output duty(%).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 0.0, 100.0);
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(
+        setpoint: f32,
+        (kp, ki, kd): (f32, f32, f32),
+        filter: f32,
+        min: f32,
+        max: f32
+    ) -> Self {
+        Self {
+            setpoint,
+            kp,
+            ki,
+            kd,
+            filter,
+            min,
+            max,
+            integral: 0.0,
+            derivative: 0.0,
+            last_temp: None
+        }
+    }
+
+    /// Feed a temperature sample taken `dt` after the previous one,
+    /// returns the duty(%).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 0.0, 100.0);
+    /// let duty = pid.update(60.0, Duration::from_secs(10));
+    /// ```
+    #[rustfmt::skip]
+    pub fn update(&mut self, temp: f32, dt: Duration) -> f32 {
+        let dt = dt.as_secs_f32();
+        let error = temp - self.setpoint;
+
+        if let Some(last) = self.last_temp {
+            if dt > 0.0 {
+                let alpha = self.filter / (self.filter + dt);
+                let raw = (temp - last) / dt;
+                self.derivative = alpha * self.derivative + (1.0 - alpha) * raw;
+            }
+        }
+
+        self.last_temp = Some(temp);
+        let mut integral = self.integral + error * dt;
+        if self.ki > 0.0 {
+            integral = integral.clamp(self.min / self.ki, self.max / self.ki);
+        }
+
+        let output = self.kp * error + self.ki * integral + self.kd * self.derivative;
+        let saturated = (output > self.max && error > 0.0)
+            || (output < self.min && error < 0.0);
+        if !saturated {
+            self.integral = integral;
+        }
+
+        output.clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// First-order thermal plant: the soc settles at `ambient` plus
+    /// `heat` divided by how much the fan cools, `1 + 3 * duty`, with
+    /// time constant `tau`(secs).
+    struct Plant {
+        temp: f32,
+        ambient: f32,
+        heat: f32,
+        tau: f32
+    }
+
+    impl Plant {
+        fn step(&mut self, duty: f32, dt: f32) -> f32 {
+            let settled = self.ambient + self.heat / (1.0 + 3.0 * duty / 100.0);
+            self.temp += (settled - self.temp) * dt / self.tau;
+            self.temp
+        }
+    }
+
+    /// Run the loop for `secs`, returns the last temperature and duty.
+    fn run(pid: &mut Pid, plant: &mut Plant, secs: u32, dt: u32) -> (f32, f32) {
+        let mut duty = 0.0;
+        for _ in 0..secs / dt {
+            let temp = plant.step(duty, dt as f32);
+            duty = pid.update(temp, Duration::from_secs(dt as u64));
+        }
+
+        (plant.temp, duty)
+    }
+
+    #[test]
+    fn settles_at_the_setpoint() {
+        let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 0.0, 100.0);
+        let mut plant = Plant { temp: 45.0, ambient: 25.0, heat: 55.0, tau: 60.0 };
+        let (temp, duty) = run(&mut pid, &mut plant, 2 * 3600, 10);
+        assert!((temp - 55.0).abs() < 0.2, "settled at {}°C", temp);
+        // 25 + 55 / (1 + 3d) = 55 holds at d = 27.8%.
+        assert!((duty - 27.8).abs() < 1.0, "settled at {}%", duty);
+    }
+
+    #[test]
+    fn recovers_from_a_load_change() {
+        let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 0.0, 100.0);
+        let mut plant = Plant { temp: 45.0, ambient: 25.0, heat: 55.0, tau: 60.0 };
+        run(&mut pid, &mut plant, 3600, 10);
+        plant.heat = 80.0;
+        let (temp, _) = run(&mut pid, &mut plant, 3600, 10);
+        assert!((temp - 55.0).abs() < 0.2, "settled at {}°C", temp);
+    }
+
+    #[test]
+    fn does_not_wind_up_at_max_duty() {
+        let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 0.0, 100.0);
+        // a load the fan cannot hold at the setpoint.
+        let mut plant = Plant { temp: 45.0, ambient: 25.0, heat: 200.0, tau: 60.0 };
+        let (temp, duty) = run(&mut pid, &mut plant, 3600, 10);
+        assert!(temp > 55.0);
+        assert_eq!(duty, 100.0);
+
+        // once below the setpoint the duty leaves the limit at once,
+        // a wound up integral would hold it there for hours.
+        let duty = pid.update(54.0, Duration::from_secs(10));
+        assert!(duty < 100.0, "still at {}%", duty);
+        assert!(pid.ki * pid.integral <= 100.0);
+    }
+
+    #[test]
+    fn does_not_wind_up_at_min_duty() {
+        let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 20.0, 80.0);
+        for _ in 0..360 {
+            assert_eq!(pid.update(40.0, Duration::from_secs(10)), 20.0);
+        }
+
+        let duty = pid.update(56.0, Duration::from_secs(10));
+        assert!(duty > 20.0, "still at {}%", duty);
+    }
+
+    #[test]
+    fn holds_the_output_range() {
+        let mut pid = Pid::new(55.0, (10.0, 0.1, 20.0), 5.0, 20.0, 80.0);
+        assert_eq!(pid.update(90.0, Duration::from_secs(10)), 80.0);
+        assert_eq!(pid.update(10.0, Duration::from_secs(10)), 20.0);
+    }
+}
@@ -0,0 +1,301 @@
+use std::time::Duration;
+use std::net::{
+    Shutdown,
+    TcpStream
+};
+
+use std::os::unix::net::UnixStream;
+use std::io::{
+    Read,
+    Write
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use super::driver::FanDriver;
+
+/// ```c
+/// #define PI_CMD_MODES 0
+/// ```
+const MODES: u32 = 0;
+
+/// ```c
+/// #define PI_CMD_PWM 5
+/// ```
+const PWM: u32 = 5;
+
+/// ```c
+/// #define PI_CMD_PRS 6
+/// ```
+const PRS: u32 = 6;
+
+/// ```c
+/// #define PI_CMD_PFS 7
+/// ```
+const PFS: u32 = 7;
+
+/// ```c
+/// #define PI_CMD_HP 86
+/// ```
+const HP: u32 = 86;
+
+/// ```c
+/// #define PI_OUTPUT 1
+/// ```
+const PI_OUTPUT: u32 = 1;
+
+/// pigpio hardware PWM duty-cycle steps.
+const HARDWARE_RANGE: u64 = 1_000_000;
+
+/// How long a command may take before the daemon is considered gone.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection to pigpiod.
+enum Socket {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl Socket {
+    fn stream(&mut self) -> &mut dyn ReadWrite {
+        match self {
+            Self::Tcp(stream) => stream,
+            Self::Unix(stream) => stream
+        }
+    }
+}
+
+trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+/// RaspberryPI fan driven through the pigpio daemon.
+///
+/// Speaks the pigpiod socket protocol, so the GPIO hardware stays
+/// owned by the daemon and other pigpio clients keep working.
+/// Every command is four little-endian `u32`: command, p1, p2 and
+/// the length of an optional extension, the daemon answers with
+/// the same header where the last field is the result.
+pub struct Pigpiod {
+    socket: Socket,
+    pin: u8,
+    frequency: u32,
+    range: u32,
+    hardware: bool,
+    duty: u32
+}
+
+impl Pigpiod {
+    /// Connect to the daemon and set up the PWM pin.
+    ///
+    /// With `hardware` the pin is driven by `HP` at any frequency, 
+    /// otherwise by the software `PWM` after `PFS` and `PRS`.
+    ///
+    /// The address is `host:port`, normally `127.0.0.1:8888`,
+    /// or the path of the unix socket, normally `/var/run/pigpio.sock`.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut fan = Pigpiod::new("127.0.0.1:8888", 12, 25000, 255, true).unwrap();
+    /// fan.set_duty(255).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(
+        address: &str, 
+        pin: u8, 
+        frequency: u32, 
+        range: u32, 
+        hardware: bool
+    ) -> Result<Self> {
+        let socket = if address.starts_with('/') {
+            let stream = UnixStream::connect(address)
+                .with_context(|| format!("cannot connect to pigpiod at {:?}", address))?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.set_write_timeout(Some(TIMEOUT))?;
+            Socket::Unix(stream)
+        } else {
+            let stream = TcpStream::connect(address)
+                .with_context(|| format!("cannot connect to pigpiod at {:?}", address))?;
+            stream.set_read_timeout(Some(TIMEOUT))?;
+            stream.set_write_timeout(Some(TIMEOUT))?;
+            stream.set_nodelay(true)?;
+            Socket::Tcp(stream)
+        };
+
+        let mut this = Self {
+            socket,
+            pin,
+            frequency,
+            range,
+            hardware,
+            duty: 0
+        };
+
+        if !hardware {
+            this.command(MODES, pin as u32, PI_OUTPUT, &[])?;
+            this.command(PFS, pin as u32, frequency, &[])?;
+            this.command(PRS, pin as u32, range, &[])?;
+        }
+
+        this.set_duty(0)?;
+        Ok(this)
+    }
+
+    /// Send a command and wait for its result.
+    #[rustfmt::skip]
+    fn command(&mut self, cmd: u32, p1: u32, p2: u32, extension: &[u8]) -> Result<u32> {
+        let mut request = Vec::with_capacity(16 + extension.len());
+        for value in [cmd, p1, p2, extension.len() as u32].iter() {
+            request.extend_from_slice(&value.to_le_bytes());
+        }
+
+        request.extend_from_slice(extension);
+        let stream = self.socket.stream();
+        stream.write_all(&request)?;
+
+        let mut response = [0u8; 16];
+        stream.read_exact(&mut response)?;
+        let mut result = [0u8; 4];
+        result.copy_from_slice(&response[12..]);
+        let result = i32::from_le_bytes(result);
+        if result < 0 {
+            return Err(anyhow!("pigpiod command {} failed: {}", cmd, result))
+        }
+
+        Ok(result as u32)
+    }
+}
+
+impl FanDriver for Pigpiod {
+    #[rustfmt::skip]
+    fn set_duty(&mut self, duty: u32) -> Result<()> {
+        if self.hardware {
+            let value = duty.min(self.range) as u64 * HARDWARE_RANGE / self.range as u64;
+            let value = (value as u32).to_le_bytes();
+            self.command(HP, self.pin as u32, self.frequency, &value)?;
+        } else {
+            self.command(PWM, self.pin as u32, duty, &[])?;
+        }
+
+        self.duty = duty;
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Close the connection, the daemon keeps the pin as it is.
+    #[rustfmt::skip]
+    fn shutdown(&mut self) -> Result<()> {
+        match &self.socket {
+            Socket::Tcp(stream) => stream.shutdown(Shutdown::Both)?,
+            Socket::Unix(stream) => stream.shutdown(Shutdown::Both)?
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::{
+        spawn,
+        JoinHandle
+    };
+
+    /// Fake pigpiod accepting one client, answering `result` to
+    /// every command. Returns its address and, once the client is
+    /// gone, every frame received.
+    fn daemon(result: i32) -> (String, JoinHandle<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut frames = Vec::new();
+            let mut header = [0u8; 16];
+            while stream.read_exact(&mut header).is_ok() {
+                let mut length = [0u8; 4];
+                length.copy_from_slice(&header[12..]);
+                let mut frame = header.to_vec();
+                frame.resize(16 + u32::from_le_bytes(length) as usize, 0);
+                stream.read_exact(&mut frame[16..]).unwrap();
+                frames.push(frame);
+
+                let mut response = header;
+                response[12..].copy_from_slice(&result.to_le_bytes());
+                stream.write_all(&response).unwrap();
+            }
+
+            frames
+        });
+
+        (address, handle)
+    }
+
+    /// Frame of a command without extension.
+    fn frame(cmd: u32, p1: u32, p2: u32) -> Vec<u8> {
+        [cmd, p1, p2, 0].iter().flat_map(|value| value.to_le_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn sets_up_software_pwm() {
+        let (address, daemon) = daemon(0);
+        let mut fan = Pigpiod::new(&address, 12, 800, 255, false).unwrap();
+        fan.set_duty(128).unwrap();
+        fan.shutdown().unwrap();
+
+        assert_eq!(daemon.join().unwrap(), vec![
+            frame(MODES, 12, PI_OUTPUT),
+            frame(PFS, 12, 800),
+            frame(PRS, 12, 255),
+            frame(PWM, 12, 0),
+            frame(PWM, 12, 128)
+        ]);
+        assert_eq!(fan.get_duty(), 128);
+    }
+
+    #[test]
+    fn drives_hardware_pwm_with_an_extension() {
+        let (address, daemon) = daemon(0);
+        let mut fan = Pigpiod::new(&address, 12, 25000, 255, true).unwrap();
+        fan.set_duty(255).unwrap();
+        fan.set_duty(51).unwrap();
+        fan.shutdown().unwrap();
+
+        let frames = daemon.join().unwrap();
+        assert_eq!(frames.len(), 3);
+        // HP, gpio 12, 25kHz, a 4 bytes extension holding 0.
+        assert_eq!(frames[0], vec![
+            86, 0, 0, 0,
+            12, 0, 0, 0,
+            0xa8, 0x61, 0, 0,
+            4, 0, 0, 0,
+            0, 0, 0, 0
+        ]);
+        // 1000000, then 200000, as little-endian u32.
+        assert_eq!(&frames[1][12..], &[4, 0, 0, 0, 0x40, 0x42, 0x0f, 0]);
+        assert_eq!(&frames[2][12..], &[4, 0, 0, 0, 0x40, 0x0d, 0x03, 0]);
+    }
+
+    #[test]
+    fn reports_a_negative_result() {
+        // PI_BAD_USER_GPIO
+        let (address, daemon) = daemon(-2);
+        let e = Pigpiod::new(&address, 12, 25000, 255, true).err().unwrap();
+        assert_eq!(e.to_string(), "pigpiod command 86 failed: -2");
+        assert_eq!(daemon.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn fails_without_a_daemon() {
+        let e = Pigpiod::new("/nonexistent/pigpio.sock", 12, 25000, 255, true).err().unwrap();
+        assert!(e.to_string().contains("cannot connect to pigpiod"));
+    }
+}
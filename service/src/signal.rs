@@ -0,0 +1,184 @@
+use std::fmt;
+use std::ptr;
+use std::thread::spawn;
+use std::time::{
+    Duration,
+    Instant
+};
+
+use std::sync::{
+    Arc,
+    Condvar,
+    Mutex
+};
+
+use anyhow::{
+    Result,
+    anyhow
+};
+
+/// Signals the service reacts to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Terminate,
+    Interrupt,
+    Hangup,
+}
+
+impl Signal {
+    #[rustfmt::skip]
+    fn from_raw(signal: libc::c_int) -> Option<Self> {
+        match signal {
+            libc::SIGTERM => Some(Self::Terminate),
+            libc::SIGINT => Some(Self::Interrupt),
+            libc::SIGHUP => Some(Self::Hangup),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Terminate => "SIGTERM",
+            Self::Interrupt => "SIGINT",
+            Self::Hangup => "SIGHUP",
+        })
+    }
+}
+
+/// Requests not handled yet.
+#[derive(Debug, Default)]
+struct Pending {
+    stop: Option<Signal>,
+    reload: bool,
+    wake: bool
+}
+
+/// Signal requests shared between the listener and the monitor.
+///
+/// SIGHUP asks for a reload, the other signals for a stop. The
+/// monitor waits on it instead of sleeping, so a request cuts the
+/// poll delay short. Clones share the same requests, which lets a
+/// test drive a monitor without sending real signals.
+///
+/// #Example
+///
+/// ```
+/// let signals = Signals::default();
+/// signals.request(Signal::Terminate);
+/// assert!(signals.wait(Duration::from_secs(10)));
+/// assert_eq!(signals.stop(), Some(Signal::Terminate));
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Signals {
+    inner: Arc<(Mutex<Pending>, Condvar)>
+}
+
+impl Signals {
+    /// Record a signal and wake every waiter.
+    #[rustfmt::skip]
+    pub fn request(&self, signal: Signal) {
+        let (pending, condvar) = &*self.inner;
+        let mut pending = pending.lock().unwrap();
+        match signal {
+            Signal::Hangup => pending.reload = true,
+            _ => pending.stop = Some(signal)
+        }
+
+        condvar.notify_all();
+    }
+
+    /// Cut the current wait short, e.g. to apply a manual duty.
+    #[rustfmt::skip]
+    pub fn wake(&self) {
+        let (pending, condvar) = &*self.inner;
+        pending.lock().unwrap().wake = true;
+        condvar.notify_all();
+    }
+
+    /// The pending stop request.
+    pub fn stop(&self) -> Option<Signal> {
+        self.inner.0.lock().unwrap().stop
+    }
+
+    /// Whether a reload was requested, clears the request.
+    pub fn take_reload(&self) -> bool {
+        std::mem::take(&mut self.inner.0.lock().unwrap().reload)
+    }
+
+    /// Sleep for `duration` or until a stop or reload is requested
+    /// or a wake-up arrives, returns whether the wait was cut short.
+    /// A wake-up is consumed by the wait it ends.
+    #[rustfmt::skip]
+    pub fn wait(&self, duration: Duration) -> bool {
+        let (pending, condvar) = &*self.inner;
+        let deadline = Instant::now() + duration;
+        let mut guard = pending.lock().unwrap();
+        loop {
+            if guard.stop.is_some() || guard.reload {
+                return true
+            }
+
+            if guard.wake {
+                guard.wake = false;
+                return true
+            }
+
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_secs(0) {
+                return false
+            }
+
+            guard = condvar.wait_timeout(guard, left).unwrap().0;
+        }
+    }
+}
+
+/// Turn SIGTERM, SIGINT and SIGHUP into requests.
+///
+/// The signals are blocked and collected by a `sigwait` thread, so
+/// this must run before any other thread is spawned for them to
+/// inherit the mask.
+///
+/// #Example
+///
+/// ```
+/// let signals = listen().unwrap();
+/// signals.wait(Duration::from_secs(10));
+/// ```
+#[rustfmt::skip]
+pub fn listen() -> Result<Signals> {
+    let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+    }
+
+    let code = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+    if code != 0 {
+        return Err(anyhow!("cannot block signals: {}", std::io::Error::from_raw_os_error(code)))
+    }
+
+    let signals = Signals::default();
+    let sink = signals.clone();
+    spawn(move || loop {
+        let mut raw = 0;
+        if unsafe { libc::sigwait(&set, &mut raw) } != 0 {
+            continue
+        }
+
+        if let Some(signal) = Signal::from_raw(raw) {
+            match signal {
+                Signal::Hangup => info!("received {}, reloading", signal),
+                _ => info!("received {}, stopping", signal)
+            }
+
+            sink.request(signal);
+        }
+    });
+
+    Ok(signals)
+}
@@ -0,0 +1,146 @@
+use std::time::Instant;
+
+/// Duty-cycle slew-rate limiter.
+///
+/// Moves the duty(%) towards its target by at most `up` %/s
+/// while rising and `down` %/s while falling, an unset rate
+/// jumps straight to the target. Starts from 0, like the fan.
+///
+/// Clock-free: every step is given the time it is taken at,
+/// so a ramp can be driven by a fake clock.
+#[derive(Debug, Clone)]
+pub struct SlewLimiter {
+    up: Option<f32>,
+    down: Option<f32>,
+    duty: f32,
+    last: Option<Instant>
+}
+
+impl SlewLimiter {
+    /// Create a limiter from rates(%/s).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// SlewLimiter::new(Some(10.0), Some(5.0));
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(up: Option<f32>, down: Option<f32>) -> Self {
+        Self {
+            up,
+            down,
+            duty: 0.0,
+            last: None
+        }
+    }
+
+    /// Step towards `target` for the time elapsed since the
+    /// previous step, returns the limited duty(%).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut slew = SlewLimiter::new(Some(10.0), None);
+    /// let start = Instant::now();
+    /// assert_eq!(slew.step(100.0, start), 0.0);
+    /// assert_eq!(slew.step(100.0, start + Duration::from_secs(2)), 20.0);
+    /// assert_eq!(slew.step(0.0, start + Duration::from_secs(3)), 0.0);
+    /// ```
+    #[rustfmt::skip]
+    pub fn step(&mut self, target: f32, now: Instant) -> f32 {
+        let elapsed = self.last
+            .map(|last| now.saturating_duration_since(last).as_secs_f32())
+            .unwrap_or(0.0);
+        self.last = Some(now);
+
+        self.duty = if target > self.duty {
+            match self.up {
+                Some(rate) => (self.duty + rate * elapsed).min(target),
+                None => target
+            }
+        } else {
+            match self.down {
+                Some(rate) => (self.duty - rate * elapsed).max(target),
+                None => target
+            }
+        };
+
+        self.duty
+    }
+
+    /// Continue from a duty(%) applied outside the limiter.
+    pub fn set(&mut self, duty: f32) {
+        self.duty = duty;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Fake clock, `secs` after a fixed start.
+    fn at(start: Instant, secs: f32) -> Instant {
+        start + Duration::from_secs_f32(secs)
+    }
+
+    #[test]
+    fn ramps_up_and_down_at_their_rates() {
+        let start = Instant::now();
+        let mut slew = SlewLimiter::new(Some(10.0), Some(5.0));
+        assert_eq!(slew.step(50.0, at(start, 0.0)), 0.0);
+        assert_eq!(slew.step(50.0, at(start, 1.0)), 10.0);
+        assert_eq!(slew.step(50.0, at(start, 3.5)), 35.0);
+        // reaches the target and stays there.
+        assert_eq!(slew.step(50.0, at(start, 10.0)), 50.0);
+        assert_eq!(slew.step(50.0, at(start, 11.0)), 50.0);
+
+        assert_eq!(slew.step(20.0, at(start, 12.0)), 45.0);
+        assert_eq!(slew.step(20.0, at(start, 14.0)), 35.0);
+        assert_eq!(slew.step(20.0, at(start, 30.0)), 20.0);
+    }
+
+    #[test]
+    fn reverses_mid_ramp_from_where_it_got() {
+        let start = Instant::now();
+        let mut slew = SlewLimiter::new(Some(10.0), Some(5.0));
+        slew.step(100.0, at(start, 0.0));
+        assert_eq!(slew.step(100.0, at(start, 4.0)), 40.0);
+        // the target drops below the duty reached, down from 40%.
+        assert_eq!(slew.step(0.0, at(start, 6.0)), 30.0);
+        // and rises again above it, up from 30%.
+        assert_eq!(slew.step(100.0, at(start, 7.0)), 40.0);
+        // a target between the duty and the rate step is not overshot.
+        assert_eq!(slew.step(45.0, at(start, 8.0)), 45.0);
+    }
+
+    #[test]
+    fn jumps_without_a_rate() {
+        let start = Instant::now();
+        let mut slew = SlewLimiter::new(None, Some(5.0));
+        assert_eq!(slew.step(80.0, at(start, 0.0)), 80.0);
+        assert_eq!(slew.step(0.0, at(start, 1.0)), 75.0);
+
+        let mut slew = SlewLimiter::new(Some(10.0), None);
+        slew.step(80.0, at(start, 0.0));
+        assert_eq!(slew.step(80.0, at(start, 2.0)), 20.0);
+        assert_eq!(slew.step(0.0, at(start, 2.5)), 0.0);
+    }
+
+    #[test]
+    fn continues_from_a_set_duty() {
+        let start = Instant::now();
+        let mut slew = SlewLimiter::new(Some(10.0), Some(5.0));
+        slew.step(0.0, at(start, 0.0));
+        slew.set(100.0);
+        assert_eq!(slew.step(0.0, at(start, 2.0)), 90.0);
+    }
+
+    #[test]
+    fn holds_on_a_clock_going_back() {
+        let start = at(Instant::now(), 60.0);
+        let mut slew = SlewLimiter::new(Some(10.0), Some(5.0));
+        slew.step(100.0, start);
+        assert_eq!(slew.step(100.0, start - Duration::from_secs(10)), 0.0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::thread::spawn;
+use std::process::Command;
+
+/// What the monitor should do about the fan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    /// The fan follows its duty-cycle, or isn't asked to spin.
+    Ok,
+    /// The fan doesn't spin, give it a full-power kick.
+    Kick,
+    /// The fan just failed after every kick.
+    Failed,
+    /// The fan is still failed.
+    StillFailed,
+    /// The failed fan spins again.
+    Recovered,
+}
+
+/// Fan stall and failure detection.
+///
+/// A fan commanded above `spin_threshold` duty(%) is stalled when
+/// it reads 0rpm, or less than `min_ratio` of the speed expected
+/// from `max_rpm` at that duty. Every stalled reading asks for a
+/// kick until `kicks` kicks went unanswered, then the fan is failed
+/// until it spins again.
+///
+/// Pure state machine: feed it the duty and the speed it produced.
+#[derive(Debug, Clone)]
+pub struct StallDetector {
+    spin_threshold: f32,
+    max_rpm: Option<f32>,
+    min_ratio: f32,
+    kicks: u32,
+    attempts: u32,
+    failed: bool
+}
+
+impl StallDetector {
+    /// Create a detector.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut stall = StallDetector::new(20.0, Some(3000.0), 0.3, 2);
+    /// assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+    /// assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+    /// assert_eq!(stall.check(50.0, 0.0), Verdict::Failed);
+    /// assert_eq!(stall.check(50.0, 1500.0), Verdict::Recovered);
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(spin_threshold: f32, max_rpm: Option<f32>, min_ratio: f32, kicks: u32) -> Self {
+        Self {
+            spin_threshold,
+            max_rpm,
+            min_ratio,
+            kicks,
+            attempts: 0,
+            failed: false
+        }
+    }
+
+    /// Judge the speed the fan reached at a duty(%).
+    #[rustfmt::skip]
+    pub fn check(&mut self, duty: f32, rpm: f32) -> Verdict {
+        let expected = self.max_rpm
+            .map(|max| max * duty / 100.0 * self.min_ratio)
+            .unwrap_or(0.0);
+        let stalled = duty >= self.spin_threshold && (rpm <= 0.0 || rpm < expected);
+        if !stalled {
+            self.attempts = 0;
+            if self.failed && duty >= self.spin_threshold {
+                self.failed = false;
+                return Verdict::Recovered
+            }
+
+            return if self.failed { Verdict::StillFailed } else { Verdict::Ok }
+        }
+
+        if self.failed {
+            return Verdict::StillFailed
+        }
+
+        if self.attempts < self.kicks {
+            self.attempts += 1;
+            return Verdict::Kick
+        }
+
+        self.failed = true;
+        Verdict::Failed
+    }
+
+    /// Whether the fan is failed.
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+}
+
+/// Run the alert command in the background.
+///
+/// The command goes through `sh -c` with the event, `fan_failed`
+/// or `fan_recovered`, in `RADIATOR_EVENT`, and the duty(%) and
+/// speed(rpm) in `RADIATOR_DUTY` and `RADIATOR_RPM`. The monitor
+/// doesn't wait for it, a command that cannot start is logged.
+///
+/// #Example
+///
+/// ```
+/// alert("logger -t radiator $RADIATOR_EVENT", "fan_failed", 60.0, 0.0);
+/// ```
+#[rustfmt::skip]
+pub fn alert(command: &str, event: &str, duty: f32, rpm: f32) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("RADIATOR_EVENT", event)
+        .env("RADIATOR_DUTY", format!("{:.0}", duty))
+        .env("RADIATOR_RPM", format!("{:.0}", rpm))
+        .spawn();
+    match child {
+        Ok(mut child) => drop(spawn(move || child.wait())),
+        Err(e) => error!("cannot run fan.stall.alert_command: {}", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kicks_then_fails_until_the_fan_spins() {
+        let mut stall = StallDetector::new(20.0, None, 0.3, 2);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+        assert!(!stall.failed());
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Failed);
+        assert!(stall.failed());
+        assert_eq!(stall.check(50.0, 0.0), Verdict::StillFailed);
+
+        // stopping the fan doesn't tell whether it recovered.
+        assert_eq!(stall.check(0.0, 0.0), Verdict::StillFailed);
+        assert_eq!(stall.check(50.0, 900.0), Verdict::Recovered);
+        assert!(!stall.failed());
+
+        // the kicks start over once it failed again.
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+    }
+
+    #[test]
+    fn spinning_again_resets_the_kicks() {
+        let mut stall = StallDetector::new(20.0, None, 0.3, 2);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+        assert_eq!(stall.check(50.0, 900.0), Verdict::Ok);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Kick);
+        assert_eq!(stall.check(50.0, 0.0), Verdict::Failed);
+    }
+
+    #[test]
+    fn ignores_a_stopped_fan_below_the_spin_threshold() {
+        let mut stall = StallDetector::new(20.0, None, 0.3, 0);
+        assert_eq!(stall.check(0.0, 0.0), Verdict::Ok);
+        assert_eq!(stall.check(19.9, 0.0), Verdict::Ok);
+        assert_eq!(stall.check(20.0, 0.0), Verdict::Failed);
+    }
+
+    #[test]
+    fn compares_the_speed_with_the_expected_ratio() {
+        // 30% of 3000rpm at 50% duty is 450rpm.
+        let mut stall = StallDetector::new(20.0, Some(3000.0), 0.3, 1);
+        assert_eq!(stall.check(50.0, 460.0), Verdict::Ok);
+        assert_eq!(stall.check(50.0, 440.0), Verdict::Kick);
+        assert_eq!(stall.check(100.0, 800.0), Verdict::Failed);
+        assert_eq!(stall.check(100.0, 950.0), Verdict::Recovered);
+
+        // without max_rpm only a stopped fan is stalled.
+        let mut stall = StallDetector::new(20.0, None, 0.3, 1);
+        assert_eq!(stall.check(100.0, 1.0), Verdict::Ok);
+    }
+}
@@ -0,0 +1,243 @@
+use std::fs;
+use std::thread::sleep;
+use std::path::{
+    Path,
+    PathBuf
+};
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use super::driver::FanDriver;
+
+/// How long udev gets to create the channel after export.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Kernel hardware PWM channel.
+///
+/// Drives `<root>/pwmchipN/pwmM`, the root is normally
+/// `/sys/class/pwm`. Needs the `pwm` or `pwm-2chan` dtoverlay,
+/// no root privileges when the udev rules grant the `gpio` group,
+/// and works on every model including the Raspberry Pi 5.
+///
+/// The duty-cycle scale `0..=range` is mapped onto nanoseconds
+/// of the period.
+pub struct SysfsPwm {
+    chip: PathBuf,
+    channel: PathBuf,
+    index: u32,
+    exported: bool,
+    period: u64,
+    range: u32,
+    duty: u32
+}
+
+impl SysfsPwm {
+    /// Export and enable a channel.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let root = Path::new("/sys/class/pwm");
+    /// let mut fan = SysfsPwm::new(root, 0, 0, 25000, 255).unwrap();
+    /// fan.set_duty(255).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(root: &Path, chip: u32, channel: u32, frequency: u32, range: u32) -> Result<Self> {
+        let chip = root.join(format!("pwmchip{}", chip));
+        let dir = chip.join(format!("pwm{}", channel));
+        let exported = !dir.exists();
+        if exported {
+            write(&chip.join("export"), channel)?;
+            let start = Instant::now();
+            while !dir.join("enable").exists() {
+                if start.elapsed() > EXPORT_TIMEOUT {
+                    return Err(anyhow!("{:?} did not appear after export", dir))
+                }
+
+                sleep(Duration::from_millis(10));
+            }
+        }
+
+        let this = Self {
+            chip,
+            channel: dir,
+            index: channel,
+            exported,
+            period: 1_000_000_000 / frequency.max(1) as u64,
+            range,
+            duty: 0
+        };
+
+        // polarity is only writable while disabled, and the duty-cycle
+        // must never exceed the period, so go through zero first.
+        this.write("enable", 0)?;
+        this.write("duty_cycle", 0)?;
+        this.write("period", this.period)?;
+        this.write("polarity", "normal")?;
+        this.write("enable", 1)?;
+        Ok(this)
+    }
+
+    /// Write an attribute of the channel.
+    fn write<T: ToString>(&self, name: &str, value: T) -> Result<()> {
+        write(&self.channel.join(name), value)
+    }
+}
+
+impl FanDriver for SysfsPwm {
+    #[rustfmt::skip]
+    fn set_duty(&mut self, duty: u32) -> Result<()> {
+        let duty = duty.min(self.range);
+        let nanos = self.period * duty as u64 / self.range.max(1) as u64;
+        self.write("duty_cycle", nanos)?;
+        self.duty = duty;
+        Ok(())
+    }
+
+    fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    /// Leave a running channel enabled, so the kernel keeps generating
+    /// the last duty-cycle after exit. A channel stopped at 0 is
+    /// disabled and unexported if it was exported here.
+    ///
+    /// Unexporting also disables the output, so keeping the fan at
+    /// `shutdown.duty` means leaving the channel exported behind us.
+    /// The next start then finds it already exported, takes it over
+    /// as is and won't unexport it either, the channel stays exported
+    /// until a shutdown at 0 or a reboot. Cooling after exit is worth
+    /// one stale `pwmN` directory.
+    #[rustfmt::skip]
+    fn shutdown(&mut self) -> Result<()> {
+        if self.duty > 0 {
+            return Ok(())
+        }
+
+        self.write("enable", 0)?;
+        if self.exported {
+            write(&self.chip.join("unexport"), self.index)?;
+            self.exported = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a sysfs attribute.
+fn write<T: ToString>(path: &Path, value: T) -> Result<()> {
+    fs::write(path, value.to_string()).with_context(|| format!("cannot write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch;
+    use std::thread::spawn;
+
+    /// Fake `/sys/class/pwm` with an empty `pwmchip0`.
+    fn sysfs(name: &str) -> PathBuf {
+        let root = scratch(&format!("pwm-{}", name));
+        fs::create_dir(root.join("pwmchip0")).unwrap();
+        root
+    }
+
+    /// Create the channel once something is written to `export`,
+    /// as udev does.
+    fn udev(root: &Path) -> std::thread::JoinHandle<()> {
+        let chip = root.join("pwmchip0");
+        spawn(move || {
+            let start = Instant::now();
+            while !chip.join("export").exists() {
+                assert!(start.elapsed() < EXPORT_TIMEOUT);
+                sleep(Duration::from_millis(1));
+            }
+
+            fs::create_dir(chip.join("pwm0")).unwrap();
+            fs::write(chip.join("pwm0/enable"), "0").unwrap();
+        })
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn exports_and_enables_the_channel() {
+        let root = sysfs("export");
+        let udev = udev(&root);
+        let mut fan = SysfsPwm::new(&root, 0, 0, 25000, 255).unwrap();
+        udev.join().unwrap();
+
+        let channel = root.join("pwmchip0/pwm0");
+        assert_eq!(read(&root.join("pwmchip0/export")), "0");
+        assert_eq!(read(&channel.join("period")), "40000");
+        assert_eq!(read(&channel.join("polarity")), "normal");
+        assert_eq!(read(&channel.join("duty_cycle")), "0");
+        assert_eq!(read(&channel.join("enable")), "1");
+
+        fan.set_duty(128).unwrap();
+        assert_eq!(read(&channel.join("duty_cycle")), "20078");
+        fan.set_duty(1000).unwrap();
+        assert_eq!(read(&channel.join("duty_cycle")), "40000");
+        assert_eq!(fan.get_duty(), 255);
+    }
+
+    #[test]
+    fn stopped_channel_is_disabled_and_unexported() {
+        let root = sysfs("stopped");
+        let udev = udev(&root);
+        let mut fan = SysfsPwm::new(&root, 0, 0, 25000, 255).unwrap();
+        udev.join().unwrap();
+
+        fan.set_duty(0).unwrap();
+        fan.shutdown().unwrap();
+        assert_eq!(read(&root.join("pwmchip0/pwm0/enable")), "0");
+        assert_eq!(read(&root.join("pwmchip0/unexport")), "0");
+    }
+
+    #[test]
+    fn running_channel_stays_enabled_and_exported() {
+        let root = sysfs("running");
+        let udev = udev(&root);
+        let mut fan = SysfsPwm::new(&root, 0, 0, 25000, 255).unwrap();
+        udev.join().unwrap();
+
+        fan.set_duty(255).unwrap();
+        fan.shutdown().unwrap();
+        assert_eq!(read(&root.join("pwmchip0/pwm0/enable")), "1");
+        assert_eq!(read(&root.join("pwmchip0/pwm0/duty_cycle")), "40000");
+        assert!(!root.join("pwmchip0/unexport").exists());
+    }
+
+    #[test]
+    fn takes_over_an_exported_channel_without_unexporting_it() {
+        let root = sysfs("taken");
+        fs::create_dir(root.join("pwmchip0/pwm1")).unwrap();
+        fs::write(root.join("pwmchip0/pwm1/enable"), "1").unwrap();
+        let mut fan = SysfsPwm::new(&root, 0, 1, 1000, 100).unwrap();
+        assert!(!root.join("pwmchip0/export").exists());
+        assert_eq!(read(&root.join("pwmchip0/pwm1/period")), "1000000");
+
+        fan.set_duty(0).unwrap();
+        fan.shutdown().unwrap();
+        assert_eq!(read(&root.join("pwmchip0/pwm1/enable")), "0");
+        assert!(!root.join("pwmchip0/unexport").exists());
+    }
+
+    #[test]
+    fn fails_when_the_channel_never_appears() {
+        let root = sysfs("missing");
+        let e = SysfsPwm::new(&root, 0, 0, 25000, 255).err().unwrap();
+        assert!(e.to_string().contains("did not appear after export"));
+    }
+}
@@ -0,0 +1,477 @@
+use std::fs::File;
+use std::path::Path;
+use std::collections::VecDeque;
+use std::io::{
+    Read,
+    Write
+};
+
+use std::thread::{
+    JoinHandle,
+    spawn
+};
+
+use std::os::unix::io::{
+    AsRawFd,
+    FromRawFd
+};
+
+use std::sync::{
+    Arc,
+    Mutex
+};
+
+use std::time::{
+    Duration,
+    Instant
+};
+
+use anyhow::{
+    Result,
+    Context,
+    anyhow
+};
+
+use super::config::{
+    TachConfig,
+    TachSource
+};
+
+#[cfg(feature = "pigpio")]
+use super::pi::PigpioTach;
+
+/// Timestamp in microseconds on the clock of a `TachInput`.
+pub type Micros = u64;
+
+/// Source of tach pulses.
+///
+/// 4-pin fans have an open-collector tach line pulled low a fixed
+/// number of times per revolution, every falling edge is one pulse.
+pub trait TachInput: Send {
+    /// Falling edges seen since the last call, oldest first,
+    /// and the current time on the same clock.
+    fn edges(&mut self) -> Result<(Vec<Micros>, Micros)>;
+
+    /// Release the line, the input is not used afterwards.
+    fn close(&mut self) {}
+}
+
+/// RPM over a sliding window of edge timestamps.
+///
+/// Pure arithmetic, no clock and no I/O: feed it edges and ask
+/// for the speed at a given time.
+#[derive(Debug, Clone)]
+pub struct Tachometer {
+    pulses_per_rev: u32,
+    window: Micros,
+    edges: VecDeque<Micros>
+}
+
+impl Tachometer {
+    /// Create a tachometer.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut meter = Tachometer::new(2, Duration::from_secs(1));
+    /// // 2 pulses per revolution every 25ms is 1200rpm.
+    /// for i in 0..40 { meter.push(i * 25_000) }
+    /// assert_eq!(meter.rpm(1_000_000), 1200.0);
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(pulses_per_rev: u32, window: Duration) -> Self {
+        Self {
+            pulses_per_rev: pulses_per_rev.max(1),
+            window: window.as_micros() as Micros,
+            edges: VecDeque::new()
+        }
+    }
+
+    /// Record a falling edge.
+    #[rustfmt::skip]
+    pub fn push(&mut self, at: Micros) {
+        if self.edges.back().map(|last| at >= *last).unwrap_or(true) {
+            self.edges.push_back(at);
+        }
+    }
+
+    /// Speed at `now`, counting the edges of the last window.
+    ///
+    /// The speed is the number of whole pulse intervals over the
+    /// time they span, so it doesn't depend on where the window
+    /// starts. Fewer than two edges in the window reads as stopped.
+    #[rustfmt::skip]
+    pub fn rpm(&mut self, now: Micros) -> f32 {
+        let start = now.saturating_sub(self.window);
+        while self.edges.front().map(|first| *first < start).unwrap_or(false) {
+            self.edges.pop_front();
+        }
+
+        match (self.edges.front(), self.edges.back()) {
+            (Some(first), Some(last)) if last > first => {
+                let revs = (self.edges.len() - 1) as f32 / self.pulses_per_rev as f32;
+                revs * 60_000_000.0 / (last - first) as f32
+            },
+            _ => 0.0
+        }
+    }
+}
+
+/// Tach input and its tachometer.
+pub struct Tach {
+    input: Box<dyn TachInput>,
+    meter: Tachometer
+}
+
+impl Tach {
+    /// Open the input selected by `tach.source`.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut tach = Tach::open(&config.fan.tach.unwrap()).unwrap();
+    /// let rpm = tach.rpm().unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn open(config: &TachConfig) -> Result<Self> {
+        let input: Box<dyn TachInput> = match config.source {
+            TachSource::Gpiochip => Box::new(GpioChip::open(&config.chip, config.pin as u32)?),
+            #[cfg(feature = "pigpio")]
+            TachSource::Pigpio => Box::new(PigpioTach::new(config.pin)?),
+            #[cfg(not(feature = "pigpio"))]
+            TachSource::Pigpio => return Err(anyhow!(
+                "fan.tach.source \"pigpio\" is not available, build with `--features pigpio`"
+            )),
+            TachSource::Script => Box::new(Script::new(
+                config.script.clone(),
+                config.pulses_per_rev
+            ))
+        };
+
+        Ok(Self::new(input, Tachometer::new(
+            config.pulses_per_rev,
+            Duration::from_secs(config.window)
+        )))
+    }
+
+    /// Create a tach from an input.
+    #[rustfmt::skip]
+    pub fn new(input: Box<dyn TachInput>, meter: Tachometer) -> Self {
+        Self {
+            input,
+            meter
+        }
+    }
+
+    /// Collect the pending edges and compute the speed.
+    #[rustfmt::skip]
+    pub fn rpm(&mut self) -> Result<f32> {
+        let (edges, now) = self.input.edges()?;
+        for at in edges {
+            self.meter.push(at);
+        }
+
+        Ok(self.meter.rpm(now))
+    }
+
+    /// Release the input before it is dropped, so that another tach
+    /// can open the same line right away.
+    pub fn close(&mut self) {
+        self.input.close();
+    }
+}
+
+/// ```c
+/// #define GPIO_GET_LINEEVENT_IOCTL _IOWR(0xB4, 0x04, struct gpioevent_request)
+/// ```
+const GPIO_GET_LINEEVENT_IOCTL: u64 = 0xC030_B404;
+
+/// ```c
+/// #define GPIOHANDLE_REQUEST_INPUT (1UL << 0)
+/// ```
+const GPIOHANDLE_REQUEST_INPUT: u32 = 1;
+
+/// ```c
+/// #define GPIOEVENT_REQUEST_FALLING_EDGE (1UL << 1)
+/// ```
+const GPIOEVENT_REQUEST_FALLING_EDGE: u32 = 1 << 1;
+
+/// ```c
+/// struct gpioevent_request {
+///     __u32 lineoffset;
+///     __u32 handleflags;
+///     __u32 eventflags;
+///     char consumer_label[32];
+///     int fd;
+/// };
+/// ```
+#[repr(C)]
+struct GpioEventRequest {
+    lineoffset: u32,
+    handleflags: u32,
+    eventflags: u32,
+    consumer_label: [u8; 32],
+    fd: libc::c_int
+}
+
+/// Kernel GPIO character device edge events.
+///
+/// Works without pigpio on any kernel with `/dev/gpiochipN`,
+/// a reader thread drains the events as they come because the
+/// kernel only queues a handful of them. Timestamps come from
+/// `CLOCK_MONOTONIC`, which the kernel uses since Linux 5.7.
+///
+/// Closing wakes the reader through an eventfd and waits for it,
+/// the line is free again once `close` returns.
+pub struct GpioChip {
+    edges: Arc<Mutex<Vec<Micros>>>,
+    wake: File,
+    reader: Option<JoinHandle<()>>
+}
+
+impl GpioChip {
+    /// Request falling edge events of a line.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// GpioChip::open(Path::new("/dev/gpiochip0"), 6).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn open(chip: &Path, line: u32) -> Result<Self> {
+        let device = File::open(chip)
+            .with_context(|| format!("cannot open {:?}", chip))?;
+        let mut request = GpioEventRequest {
+            lineoffset: line,
+            handleflags: GPIOHANDLE_REQUEST_INPUT,
+            eventflags: GPIOEVENT_REQUEST_FALLING_EDGE,
+            consumer_label: [0; 32],
+            fd: -1
+        };
+
+        request.consumer_label[..8].copy_from_slice(b"radiator");
+        if unsafe { libc::ioctl(device.as_raw_fd(), GPIO_GET_LINEEVENT_IOCTL as _, &mut request) } < 0 {
+            return Err(anyhow!(
+                "cannot request edge events of line {} on {:?}: {}",
+                line, chip, std::io::Error::last_os_error()
+            ))
+        }
+
+        let mut events = unsafe { File::from_raw_fd(request.fd) };
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(anyhow!("cannot create an eventfd: {}", std::io::Error::last_os_error()))
+        }
+
+        let wake = unsafe { File::from_raw_fd(wake) };
+        let woken = wake.try_clone()?;
+        let edges = Arc::new(Mutex::new(Vec::new()));
+        let sink = edges.clone();
+        let reader = spawn(move || {
+            // struct gpioevent_data { __u64 timestamp; __u32 id; }
+            let mut event = [0u8; 16];
+            let mut poll = [
+                libc::pollfd { fd: events.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+                libc::pollfd { fd: woken.as_raw_fd(), events: libc::POLLIN, revents: 0 }
+            ];
+
+            loop {
+                if unsafe { libc::poll(poll.as_mut_ptr(), 2, -1) } < 0 {
+                    match std::io::Error::last_os_error().kind() {
+                        std::io::ErrorKind::Interrupted => continue,
+                        _ => break
+                    }
+                }
+
+                if poll[1].revents != 0 || events.read_exact(&mut event).is_err() {
+                    break
+                }
+
+                let mut nanos = [0u8; 8];
+                nanos.copy_from_slice(&event[..8]);
+                sink.lock().unwrap().push(u64::from_ne_bytes(nanos) / 1000);
+            }
+        });
+
+        Ok(Self {
+            edges,
+            wake,
+            reader: Some(reader)
+        })
+    }
+}
+
+impl TachInput for GpioChip {
+    #[rustfmt::skip]
+    fn edges(&mut self) -> Result<(Vec<Micros>, Micros)> {
+        let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        let now = now.tv_sec as Micros * 1_000_000 + now.tv_nsec as Micros / 1000;
+        let edges = std::mem::take(&mut *self.edges.lock().unwrap());
+        Ok((edges, now))
+    }
+
+    fn close(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            let _ = self.wake.write_all(&1u64.to_ne_bytes());
+            let _ = reader.join();
+        }
+    }
+}
+
+impl Drop for GpioChip {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Scripted input replaying a sequence of speeds.
+///
+/// Every call takes the next speed(rpm) of the script, looping
+/// back to the first after the last one, and synthesizes evenly
+/// spaced edges at that speed since the previous call, a 0 in
+/// the script plays a stalled fan.
+pub struct Script {
+    speeds: Vec<f32>,
+    cursor: usize,
+    pulses_per_rev: u32,
+    epoch: Instant,
+    next: Micros
+}
+
+impl Script {
+    /// Create a script from speeds(rpm).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut tach = Tach::new(
+    ///     Box::new(Script::new(vec![1200.0, 0.0], 2)),
+    ///     Tachometer::new(2, Duration::from_secs(3))
+    /// );
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(speeds: Vec<f32>, pulses_per_rev: u32) -> Self {
+        Self {
+            speeds,
+            cursor: 0,
+            pulses_per_rev: pulses_per_rev.max(1),
+            epoch: Instant::now(),
+            next: 0
+        }
+    }
+}
+
+impl TachInput for Script {
+    #[rustfmt::skip]
+    fn edges(&mut self) -> Result<(Vec<Micros>, Micros)> {
+        let now = self.epoch.elapsed().as_micros() as Micros;
+        let rpm = self.speeds
+            .get(self.cursor % self.speeds.len().max(1))
+            .copied()
+            .unwrap_or(0.0);
+        self.cursor += 1;
+
+        let mut edges = Vec::new();
+        if rpm > 0.0 {
+            let interval = (60_000_000.0 / (rpm * self.pulses_per_rev as f32)).max(1.0) as Micros;
+            while self.next <= now {
+                edges.push(self.next);
+                self.next += interval;
+            }
+        } else {
+            self.next = now;
+        }
+
+        Ok((edges, now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tachometer fed evenly spaced edges.
+    fn meter(pulses_per_rev: u32, window: u64, edges: impl Iterator<Item = Micros>) -> Tachometer {
+        let mut meter = Tachometer::new(pulses_per_rev, Duration::from_secs(window));
+        for at in edges {
+            meter.push(at);
+        }
+
+        meter
+    }
+
+    #[test]
+    fn counts_pulse_intervals() {
+        // 2 pulses per revolution every 10ms is 3000rpm.
+        let mut meter = meter(2, 1, (0..=100).map(|i| i * 10_000));
+        assert_eq!(meter.rpm(1_000_000), 3000.0);
+        // the same edges with 1 pulse per revolution are twice as fast.
+        let mut meter = self::meter(1, 1, (0..=100).map(|i| i * 10_000));
+        assert_eq!(meter.rpm(1_000_000), 6000.0);
+    }
+
+    #[test]
+    fn reads_stopped_without_pulses() {
+        assert_eq!(meter(2, 1, std::iter::empty()).rpm(1_000_000), 0.0);
+    }
+
+    #[test]
+    fn reads_stopped_with_a_single_pulse() {
+        assert_eq!(meter(2, 1, std::iter::once(500_000)).rpm(1_000_000), 0.0);
+        // two edges at the same instant span no time either.
+        assert_eq!(meter(2, 1, vec![500_000, 500_000].into_iter()).rpm(1_000_000), 0.0);
+    }
+
+    #[test]
+    fn keeps_the_edge_at_the_window_start() {
+        // edges every 100ms, the one at exactly now - window counts.
+        let mut meter = meter(1, 1, (0..=10).map(|i| i * 100_000));
+        assert_eq!(meter.rpm(1_000_000), 600.0);
+        assert_eq!(meter.edges.len(), 11);
+
+        // 1µs later it falls out, the speed doesn't change.
+        assert_eq!(meter.rpm(1_000_001), 600.0);
+        assert_eq!(meter.edges.len(), 10);
+    }
+
+    #[test]
+    fn slows_down_to_stopped_as_edges_age_out() {
+        let mut meter = meter(1, 1, (0..=10).map(|i| i * 100_000));
+        // only the edges at 0.9s and 1s are left.
+        assert_eq!(meter.rpm(1_900_000), 600.0);
+        assert_eq!(meter.rpm(1_900_001), 0.0);
+        assert_eq!(meter.rpm(10_000_000), 0.0);
+        assert!(meter.edges.is_empty());
+    }
+
+    #[test]
+    fn ignores_edges_going_back_in_time() {
+        let mut meter = meter(1, 1, vec![100_000, 200_000, 150_000, 300_000].into_iter());
+        assert_eq!(meter.rpm(300_000), 600.0);
+    }
+
+    #[test]
+    fn treats_zero_pulses_per_rev_as_one() {
+        let mut meter = meter(0, 1, (0..=10).map(|i| i * 100_000));
+        assert_eq!(meter.rpm(1_000_000), 600.0);
+    }
+
+    /// Input handing out fixed edges once.
+    struct Edges(Vec<Micros>, Micros);
+
+    impl TachInput for Edges {
+        fn edges(&mut self) -> Result<(Vec<Micros>, Micros)> {
+            Ok((std::mem::take(&mut self.0), self.1))
+        }
+    }
+
+    #[test]
+    fn collects_the_input_edges() {
+        let input = Edges((0..=40).map(|i| i * 25_000).collect(), 1_000_000);
+        let mut tach = Tach::new(Box::new(input), Tachometer::new(2, Duration::from_secs(1)));
+        assert_eq!(tach.rpm().unwrap(), 1200.0);
+        // nothing new, the window still holds the same edges.
+        assert_eq!(tach.rpm().unwrap(), 1200.0);
+    }
+}
@@ -1,85 +1,439 @@
-use anyhow::Result;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::error::Error;
+use std::thread::sleep;
+use std::time::{
+    Duration,
+    Instant
+};
+use std::path::{
+    Path,
+    PathBuf
+};
+
 use std::process::{
-    Output,
-    Command
+    Command,
+    Stdio
+};
+
+use super::config::{
+    SensorConfig,
+    SensorSource,
+    Zone
 };
 
+/// Temperature reading(°C).
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Celsius(pub f32);
+
+/// Why a temperature could not be read.
+#[derive(Debug)]
+pub enum SensorError {
+    /// The sensor could not be reached.
+    Io(io::Error),
+    /// The sensor command exited with an error, with its stderr.
+    Command(String),
+    /// The sensor command did not answer in time and was killed.
+    Timeout(Duration),
+    /// The sensor answered something that is not a temperature.
+    Parse(String),
+}
+
+impl SensorError {
+    /// Short name of the variant, e.g. for metric labels.
+    #[rustfmt::skip]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::Command(_) => "command",
+            Self::Timeout(_) => "timeout",
+            Self::Parse(_) => "parse"
+        }
+    }
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "sensor unreachable: {}", e),
+            Self::Command(stderr) => write!(f, "sensor command failed: {}", stderr),
+            Self::Timeout(timeout) => write!(f, "sensor command timed out after {:?}", timeout),
+            Self::Parse(output) => write!(f, "invalid sensor output {:?}", output),
+        }
+    }
+}
+
+impl Error for SensorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Command(_) | Self::Timeout(_) | Self::Parse(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for SensorError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Soc temperature source.
+pub trait TemperatureSource: Send {
+    /// Take one reading.
+    fn read(&mut self) -> Result<Celsius, SensorError>;
+}
+
+/// Open the source selected by `sensor.source`.
+///
+/// #Example
+///
+/// ```
+/// let mut source = open(&SensorConfig::default()).unwrap();
+/// let Celsius(temp) = source.read().unwrap();
+/// ```
+#[rustfmt::skip]
+pub fn open(config: &SensorConfig) -> Result<Box<dyn TemperatureSource>, SensorError> {
+    Ok(match config.source {
+        SensorSource::Vcgencmd => Box::new(Vcgencmd::new(
+            &config.vcgencmd,
+            Duration::from_millis(config.timeout)
+        )),
+        SensorSource::Sysfs => Box::new(
+            ThermalZone::open(&config.sysfs_root, &config.zone)?
+        ),
+        SensorSource::Script => Box::new(
+            Script::new(config.script.clone())
+        )
+    })
+}
+
 /// Measuring temperature.
-/// 
-/// Due to the architecture of the SoCs used on the Raspberry Pi range, 
-/// and the use of the upstream temperature monitoring code in the 
-/// Raspberry Pi OS distribution, Linux-based temperature measurements 
-/// can be inaccurate. There is a command that can provide an accurate 
-/// and instantaneous reading of the current SoC temperature, as it 
+///
+/// Due to the architecture of the SoCs used on the Raspberry Pi range,
+/// and the use of the upstream temperature monitoring code in the
+/// Raspberry Pi OS distribution, Linux-based temperature measurements
+/// can be inaccurate. There is a command that can provide an accurate
+/// and instantaneous reading of the current SoC temperature, as it
 /// communicates with the GPU directly:
 ///
 /// ```bash
 /// vcgencmd measure_temp
 /// ```
-#[rustfmt::skip]
-pub fn get_temp() -> Result<f32> {
-    let Output { 
-        stdout, 
-        stderr: _, 
-        status: _ 
-    } = Command::new("vcgencmd")
-        .arg("measure_temp")
-        .output()?;
-    let rate = String::from_utf8_lossy(&stdout)
-        .split('=')
-        .next_back()
-        .unwrap_or("0'c")
-        .split('\'')
-        .next()
-        .unwrap_or("0")
-        .trim()
-        .parse::<f32>()
-        .unwrap_or(0.0);
-    Ok(rate)
-}
-
-/// Compute duty-cycle.
-/// 
-/// All Raspberry Pi models perform a degree of thermal management 
-/// to avoid overheating under heavy load. The SoCs have an internal 
-/// temperature sensor, which software on the GPU polls to ensure that 
-/// temperatures do not exceed a predefined limit; this is 85°C on 
-/// all models. It is possible to set this to a lower value, but not 
-/// to a higher one. As the device approaches the limit, various 
-/// frequencies and sometimes voltages used on the chip (ARM, GPU) are 
-/// reduced. This reduces the amount of heat generated, keeping 
-/// the temperature under control.
-/// 
-/// When the core temperature is between 80°C and 85°C, a warning icon 
-/// showing a red half-filled thermometer will be displayed, and the 
-/// ARM cores will be progressively throttled back. If the temperature 
-/// reaches 85°C, an icon showing a fully filled thermometer will be 
-/// displayed, and both the ARM cores and the GPU will be throttled back. 
-/// See the page on warning icons for images of the icons.
-/// 
-/// For Raspberry Pi 3 Model B+, the PCB technology has been changed to 
-/// provide better heat dissipation and increased thermal mass. In addition, 
-/// a soft temperature limit has been introduced, with the goal of 
-/// maximising the time for which a device can "sprint" before reaching 
-/// the hard limit at 85°C. When the soft limit is reached, the clock 
-/// speed is reduced from 1.4GHz to 1.2GHz, and the operating voltage is 
-/// reduced slightly. This reduces the rate of temperature increase: 
-/// we trade a short period at 1.4GHz for a longer period at 1.2GHz. 
-/// By default, the soft limit is 60°C.
-/// 
-/// The Raspberry Pi 4 Model B continues with the same PCB technology 
-/// as the Raspberry Pi 3B+ to help dissipate excess heat. 
-/// There is currently no soft limit defined.
 ///
-/// #Example
+/// Which answers `temp=47.2'C`, anything else is an error.
 ///
-/// ```
-/// let temp = get_temp().unwrap();
-/// let dutycycle = get_pwm(temp);
-/// ```
-#[rustfmt::skip]
-pub fn get_pwm(temp: f32) -> u8 {
-    if temp <= 40.0 { return 0 }
-    if temp >= 60.0 { return 255 }
-    ((temp - 40.0) * 12.75).ceil() as u8
+/// The VideoCore mailbox can wedge under heavy GPU load, so the
+/// command is killed when it takes longer than the timeout.
+pub struct Vcgencmd {
+    program: PathBuf,
+    timeout: Duration
+}
+
+impl Vcgencmd {
+    /// Run `program measure_temp` with a timeout.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut source = Vcgencmd::new(Path::new("vcgencmd"), Duration::from_secs(2));
+    /// let Celsius(temp) = source.read().unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(program: &Path, timeout: Duration) -> Self {
+        Self {
+            program: program.to_path_buf(),
+            timeout
+        }
+    }
+
+    /// Parse the output of `vcgencmd measure_temp`.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// assert_eq!(Vcgencmd::parse("temp=47.2'C\n").unwrap(), Celsius(47.2));
+    /// assert!(Vcgencmd::parse("VCHI initialization failed").is_err());
+    /// ```
+    #[rustfmt::skip]
+    pub fn parse(output: &str) -> Result<Celsius, SensorError> {
+        let output = output.trim();
+        output.strip_prefix("temp=")
+            .and_then(|rest| rest.strip_suffix("'C"))
+            .and_then(|value| value.parse::<f32>().ok())
+            .filter(|value| value.is_finite())
+            .map(Celsius)
+            .ok_or_else(|| SensorError::Parse(output.to_string()))
+    }
+}
+
+impl TemperatureSource for Vcgencmd {
+    #[rustfmt::skip]
+    fn read(&mut self) -> Result<Celsius, SensorError> {
+        let mut child = Command::new(&self.program)
+            .arg("measure_temp")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status
+            }
+
+            if start.elapsed() >= self.timeout {
+                child.kill()?;
+                child.wait()?;
+                return Err(SensorError::Timeout(self.timeout))
+            }
+
+            sleep(Duration::from_millis(10));
+        };
+
+        // the output is one short line, it never fills the pipes.
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        if let Some(pipe) = &mut child.stdout {
+            pipe.read_to_end(&mut stdout)?;
+        }
+
+        if let Some(pipe) = &mut child.stderr {
+            pipe.read_to_end(&mut stderr)?;
+        }
+
+        if !status.success() {
+            return Err(SensorError::Command(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            )))
+        }
+
+        Self::parse(&String::from_utf8_lossy(&stdout))
+    }
+}
+
+/// Kernel thermal zone.
+///
+/// Reads `<root>/thermal_zoneN/temp` in millidegrees, the root is
+/// normally `/sys/class/thermal`. Works on any distro without the
+/// Raspberry Pi userland and costs no process spawn.
+pub struct ThermalZone {
+    path: PathBuf
+}
+
+impl ThermalZone {
+    /// Find a zone by index or by its `type` name.
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let root = Path::new("/sys/class/thermal");
+    /// ThermalZone::open(root, &Zone::Type("cpu-thermal".to_string())).unwrap();
+    /// ```
+    #[rustfmt::skip]
+    pub fn open(root: &Path, zone: &Zone) -> Result<Self, SensorError> {
+        let dir = match zone {
+            Zone::Index(index) => root.join(format!("thermal_zone{}", index)),
+            Zone::Type(name) => fs::read_dir(root)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.starts_with("thermal_zone"))
+                    .unwrap_or(false))
+                .find(|path| fs::read_to_string(path.join("type"))
+                    .map(|t| t.trim() == name)
+                    .unwrap_or(false))
+                .ok_or_else(|| io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no thermal zone of type {:?} in {:?}", name, root)
+                ))?
+        };
+
+        let mut this = Self {
+            path: dir.join("temp")
+        };
+
+        this.read()?;
+        Ok(this)
+    }
+}
+
+impl TemperatureSource for ThermalZone {
+    #[rustfmt::skip]
+    fn read(&mut self) -> Result<Celsius, SensorError> {
+        let text = fs::read_to_string(&self.path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{:?}: {}", self.path, e)))?;
+        let millis = text.trim()
+            .parse::<i64>()
+            .map_err(|_| SensorError::Parse(text.trim().to_string()))?;
+        Ok(Celsius(millis as f32 / 1000.0))
+    }
+}
+
+/// Scripted source replaying a fixed sequence of readings.
+///
+/// Loops back to the first reading after the last one, a
+/// non-finite reading (`nan`) is replayed as a parse error,
+/// e.g. to rehearse the failsafe.
+pub struct Script {
+    readings: Vec<f32>,
+    cursor: usize
+}
+
+impl Script {
+    /// Create a script from readings(°C).
+    ///
+    /// #Example
+    ///
+    /// ```
+    /// let mut source = Script::new(vec![40.0, f32::NAN, 60.0]);
+    /// assert_eq!(source.read().unwrap(), Celsius(40.0));
+    /// assert!(source.read().is_err());
+    /// ```
+    #[rustfmt::skip]
+    pub fn new(readings: Vec<f32>) -> Self {
+        Self {
+            readings,
+            cursor: 0
+        }
+    }
+}
+
+impl TemperatureSource for Script {
+    #[rustfmt::skip]
+    fn read(&mut self) -> Result<Celsius, SensorError> {
+        let reading = *self.readings
+            .get(self.cursor % self.readings.len().max(1))
+            .ok_or_else(|| SensorError::Parse("empty script".to_string()))?;
+        self.cursor += 1;
+        if reading.is_finite() {
+            Ok(Celsius(reading))
+        } else {
+            Err(SensorError::Parse(reading.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::scratch;
+
+    /// Fake `/sys/class/thermal` with `(type, temp)` zones.
+    fn sysfs(name: &str, zones: &[(&str, &str)]) -> PathBuf {
+        let root = scratch(&format!("thermal-{}", name));
+        for (index, (kind, temp)) in zones.iter().enumerate() {
+            let zone = root.join(format!("thermal_zone{}", index));
+            fs::create_dir(&zone).unwrap();
+            fs::write(zone.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(zone.join("temp"), format!("{}\n", temp)).unwrap();
+        }
+
+        // not a zone, must be skipped while looking up a type.
+        fs::create_dir(root.join("cooling_device0")).unwrap();
+        fs::write(root.join("cooling_device0/type"), "cpu-thermal\n").unwrap();
+        root
+    }
+
+    /// Executable shell script standing in for vcgencmd.
+    fn command(name: &str, body: &str) -> Vcgencmd {
+        use std::os::unix::fs::PermissionsExt;
+        let path = scratch(&format!("vcgencmd-{}", name)).join("vcgencmd");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        Vcgencmd::new(&path, Duration::from_millis(200))
+    }
+
+    /// Read, retrying while a test thread forking elsewhere still
+    /// holds the freshly written script open.
+    fn read(source: &mut Vcgencmd) -> Result<Celsius, SensorError> {
+        loop {
+            match source.read() {
+                Err(SensorError::Io(e)) if e.raw_os_error() == Some(libc::ETXTBSY) => sleep(Duration::from_millis(10)),
+                result => return result
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_command_output() {
+        let mut source = command("answer", "echo \"temp=47.2'C\"");
+        assert_eq!(read(&mut source).unwrap(), Celsius(47.2));
+    }
+
+    #[test]
+    fn kills_a_slow_command() {
+        let mut source = command("slow", "sleep 5");
+        let started = Instant::now();
+        let e = read(&mut source).err().unwrap();
+        assert_eq!(e.kind(), "timeout");
+        assert_eq!(e.to_string(), "sensor command timed out after 200ms");
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn reports_a_failed_command() {
+        let mut source = command("failed", "echo 'VCHI initialization failed' >&2; exit 1");
+        let e = read(&mut source).err().unwrap();
+        assert_eq!(e.kind(), "command");
+        assert!(e.to_string().ends_with("VCHI initialization failed"));
+
+        let mut source = command("garbage", "echo 'error=1 error_msg=\"Command not registered\"'");
+        assert_eq!(read(&mut source).err().unwrap().kind(), "parse");
+
+        let mut source = Vcgencmd::new(Path::new("/nonexistent/vcgencmd"), Duration::from_millis(200));
+        assert_eq!(source.read().err().unwrap().kind(), "io");
+    }
+
+    #[test]
+    fn opens_a_zone_by_index() {
+        let root = sysfs("index", &[("cpu-thermal", "47200"), ("gpu-thermal", "51000")]);
+        let mut zone = ThermalZone::open(&root, &Zone::Index(1)).unwrap();
+        assert_eq!(zone.read().unwrap(), Celsius(51.0));
+    }
+
+    #[test]
+    fn opens_a_zone_by_type() {
+        let root = sysfs("type", &[("gpu-thermal", "51000"), ("cpu-thermal", "47200")]);
+        let mut zone = ThermalZone::open(&root, &Zone::Type("cpu-thermal".to_string())).unwrap();
+        assert_eq!(zone.read().unwrap(), Celsius(47.2));
+
+        fs::write(root.join("thermal_zone1/temp"), "-5500\n").unwrap();
+        assert_eq!(zone.read().unwrap(), Celsius(-5.5));
+    }
+
+    #[test]
+    fn reports_a_missing_zone_as_io() {
+        let root = sysfs("missing", &[("cpu-thermal", "47200")]);
+        let by_index = ThermalZone::open(&root, &Zone::Index(3)).err().unwrap();
+        assert_eq!(by_index.kind(), "io");
+
+        let by_type = ThermalZone::open(&root, &Zone::Type("soc".to_string())).err().unwrap();
+        assert_eq!(by_type.kind(), "io");
+        assert!(by_type.to_string().contains("\"soc\""));
+
+        let no_root = ThermalZone::open(&root.join("nothing"), &Zone::Type("soc".to_string()));
+        assert_eq!(no_root.err().unwrap().kind(), "io");
+    }
+
+    #[test]
+    fn reports_garbage_as_parse() {
+        let root = sysfs("garbage", &[("cpu-thermal", "hot")]);
+        let e = ThermalZone::open(&root, &Zone::Index(0)).err().unwrap();
+        assert_eq!(e.kind(), "parse");
+        assert_eq!(e.to_string(), "invalid sensor output \"hot\"");
+    }
+
+    #[test]
+    fn reports_a_vanished_zone_as_io() {
+        let root = sysfs("vanished", &[("cpu-thermal", "47200")]);
+        let mut zone = ThermalZone::open(&root, &Zone::Index(0)).unwrap();
+        fs::remove_file(root.join("thermal_zone0/temp")).unwrap();
+        assert_eq!(zone.read().err().unwrap().kind(), "io");
+    }
 }
@@ -0,0 +1,81 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Duration;
+use std::path::PathBuf;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicBool,
+    Ordering
+};
+
+use anyhow::Result;
+
+use super::monitor::Status;
+use super::tach::{
+    Micros,
+    Tach,
+    TachInput,
+    Tachometer
+};
+
+/// Fresh empty directory for one test, `name` tells the tests
+/// apart, e.g. `control-mode`.
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("radiator-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Datagram socket standing in for systemd's `$NOTIFY_SOCKET`,
+/// and its path.
+pub fn notify_socket(name: &str) -> (UnixDatagram, String) {
+    let path = scratch(name).join("notify.sock");
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    (socket, path.to_str().unwrap().to_string())
+}
+
+/// Next datagram received on a socket.
+pub fn receive(socket: &UnixDatagram) -> String {
+    let mut buf = [0; 256];
+    let size = socket.recv(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..size]).to_string()
+}
+
+/// Status of a healthy fan at 52.5°C, 60% and 1800rpm.
+pub fn status() -> Status {
+    Status {
+        temp: Some(52.5),
+        duty: 60.0,
+        rpm: Some(1800.0),
+        fan_failed: false,
+        sensor_failures: 0,
+        manual: None,
+        manual_expires: None
+    }
+}
+
+/// Tach input without pulses that flags when it is closed.
+struct Tracked {
+    closed: Arc<AtomicBool>
+}
+
+impl TachInput for Tracked {
+    fn edges(&mut self) -> Result<(Vec<Micros>, Micros)> {
+        Ok((Vec::new(), 0))
+    }
+
+    fn close(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Tach reading a stopped fan, and whether it was closed.
+pub fn tracked_tach() -> (Tach, Arc<AtomicBool>) {
+    let closed = Arc::new(AtomicBool::new(false));
+    let input = Tracked { closed: closed.clone() };
+    (Tach::new(Box::new(input), Tachometer::new(2, Duration::from_secs(1))), closed)
+}